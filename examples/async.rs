@@ -19,7 +19,7 @@ fn main() {
             ldap.simple_bind("cn=root,dc=plabs".to_string(), "asdf".to_string())
         })
         .map(|res| {
-            if res {
+            if res.rc.is_success() {
                 println!("Bind succeeded!");
             } else {
                 println!("Bind failed! :(");
@@ -9,7 +9,7 @@ pub fn main() {
 
     let res = ldap.simple_bind("cn=root,dc=plabs".to_string(), "asdf".to_string()).unwrap();
 
-    if res {
+    if res.rc.is_success() {
         println!("Bind succeeded!");
         let res2 = ldap.search("dc=plabs".to_string(),
                                ldap::Scope::WholeSubtree,
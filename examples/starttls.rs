@@ -0,0 +1,22 @@
+extern crate ldap;
+extern crate native_tls;
+
+use ldap::LdapSync;
+use native_tls::TlsConnector;
+
+pub fn main() {
+    let addr = "127.0.0.1:389".parse().unwrap();
+
+    let mut ldap = LdapSync::connect(&addr).unwrap();
+
+    let connector = TlsConnector::builder().unwrap().build().unwrap();
+    ldap.start_tls(connector, "ldap.example.org").unwrap();
+
+    let res = ldap.simple_bind("cn=root,dc=example,dc=org".to_string(), "secret".to_string()).unwrap();
+
+    if res.rc.is_success() {
+        println!("Bind succeeded!");
+    } else {
+        println!("Bind failed! :(");
+    }
+}
@@ -0,0 +1,89 @@
+use asnom::structures::{Tag, Integer};
+use asnom::common::TagClass::*;
+
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use tokio_service::Service;
+
+use error::LdapError;
+use ldap::Ldap;
+
+impl Ldap {
+    /// Ask the server to stop processing the operation with the given message
+    /// ID. The server doesn't respond to an Abandon, so the returned future
+    /// resolves once the request has been sent.
+    pub fn abandon(&self, msgid: u64) -> Box<Future<Item = (), Error = LdapError>> {
+        let req = Tag::Integer(Integer {
+            id: 16,
+            class: Application,
+            inner: msgid as i64,
+        });
+
+        let fut = self.call(req).map(|_| ());
+
+        Box::new(fut)
+    }
+}
+
+/// Wrapper around the future or stream of an operation which abandons the
+/// operation if it's dropped before completing.
+pub struct Abandonable<F> {
+    inner: F,
+    ldap: Ldap,
+    msgid: oneshot::Receiver<u64>,
+    done: bool,
+}
+
+impl<F> Abandonable<F> {
+    pub fn new(inner: F, ldap: Ldap, msgid: oneshot::Receiver<u64>) -> Abandonable<F> {
+        Abandonable {
+            inner: inner,
+            ldap: ldap,
+            msgid: msgid,
+            done: false,
+        }
+    }
+}
+
+impl<F: Future> Future for Abandonable<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = self.inner.poll();
+        match res {
+            Ok(Async::NotReady) => (),
+            _ => self.done = true,
+        }
+        res
+    }
+}
+
+impl<S: Stream> Stream for Abandonable<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let res = self.inner.poll();
+        match res {
+            Ok(Async::NotReady) | Ok(Async::Ready(Some(_))) => (),
+            _ => self.done = true,
+        }
+        res
+    }
+}
+
+impl<F> Drop for Abandonable<F> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        // If the request hasn't reached the transport yet there is no message
+        // ID to abandon.
+        if let Ok(Some(msgid)) = self.msgid.try_recv() {
+            // The request is queued as soon as it's made, so the future
+            // doesn't have to be driven.
+            let _ = self.ldap.abandon(msgid);
+        }
+    }
+}
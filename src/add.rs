@@ -0,0 +1,47 @@
+use asnom::structures::{Tag, Sequence, Set, OctetString};
+use asnom::common::TagClass::*;
+
+use futures::Future;
+use tokio_service::Service;
+
+use error::LdapError;
+use ldap::Ldap;
+use result::{LdapResult, parse_result};
+
+impl Ldap {
+    pub fn add(&self, dn: String, attributes: Vec<(String, Vec<String>)>) ->
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        let req = Tag::Sequence(Sequence {
+            id: 8,
+            class: Application,
+            inner: vec![
+                   Tag::OctetString(OctetString {
+                       inner: dn.into_bytes(),
+                       .. Default::default()
+                   }),
+                   Tag::Sequence(Sequence {
+                       inner: attributes.into_iter().map(|(name, values)|
+                            Tag::Sequence(Sequence {
+                                inner: vec![
+                                       Tag::OctetString(OctetString {
+                                           inner: name.into_bytes(),
+                                           .. Default::default()
+                                       }),
+                                       Tag::Set(Set {
+                                           inner: values.into_iter().map(|v|
+                                                Tag::OctetString(OctetString { inner: v.into_bytes(), .. Default::default() })).collect(),
+                                           .. Default::default()
+                                       })
+                                ],
+                                .. Default::default()
+                            })).collect(),
+                       .. Default::default()
+                   })
+            ],
+        });
+
+        let fut = self.call(req).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+}
@@ -1,56 +1,260 @@
-use std::io;
 
+use asnom::structure::StructureTag;
 use asnom::structures::{Tag, Sequence, Integer, OctetString};
 
 use asnom::common::TagClass::*;
 
-use asnom::structures::ASNTag;
-
-use futures::Future;
+use futures::{future, Future};
+use futures::future::Loop;
 use tokio_service::Service;
 
+use error::{LdapError, decoding_error};
+use layer::SecurityLayer;
 use ldap::Ldap;
+use protocol::LdapOp;
+use result::{LdapResult, ResultCode, parse_result, parse_result_components};
 use service::LdapMessage;
 
+/// A SASL mechanism (RFC 4422) usable with `Ldap::sasl_bind`.
+///
+/// The mechanism carries the credentials it authenticates with, and is
+/// driven through as many BindRequests as the exchange takes.
+pub trait SaslMechanism {
+    /// Registered name of the mechanism, e.g. "PLAIN".
+    fn name(&self) -> &str;
+
+    /// Credentials sent with the first BindRequest, if any.
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, LdapError>;
+
+    /// Respond to a challenge sent by the server along with saslBindInProgress.
+    fn step(&mut self, challenge: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, LdapError>;
+
+    /// Check the data sent by the server with a successful BindResponse.
+    fn finish(&mut self, _server_creds: Option<Vec<u8>>) -> Result<(), LdapError> {
+        Ok(())
+    }
+
+    /// Security layer to install if the server answers the next BindRequest
+    /// with success.
+    fn security_layer(&self) -> Option<Box<SecurityLayer>> {
+        None
+    }
+}
+
+/// The PLAIN mechanism (RFC 4616). The password is sent in the clear, so it
+/// should only be used over TLS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaslPlain {
+    pub authzid: Option<String>,
+    pub authcid: String,
+    pub password: String,
+}
+
+impl SaslPlain {
+    pub fn new(authcid: String, password: String) -> SaslPlain {
+        SaslPlain {
+            authzid: None,
+            authcid: authcid,
+            password: password,
+        }
+    }
+}
+
+impl SaslMechanism for SaslPlain {
+    fn name(&self) -> &str {
+        "PLAIN"
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, LdapError> {
+        let mut message = Vec::new();
+        if let Some(ref authzid) = self.authzid {
+            message.extend_from_slice(authzid.as_bytes());
+        }
+        message.push(0);
+        message.extend_from_slice(self.authcid.as_bytes());
+        message.push(0);
+        message.extend_from_slice(self.password.as_bytes());
+        Ok(Some(message))
+    }
+
+    fn step(&mut self, _challenge: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, LdapError> {
+        Err(LdapError::Sasl("unexpected challenge for PLAIN".to_string()))
+    }
+}
+
+/// The EXTERNAL mechanism (RFC 4422 Appendix A), authenticating with
+/// credentials established outside of LDAP, like a TLS client certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaslExternal {
+    /// Identity to act as, if different from the one derived from the
+    /// external credentials.
+    pub authzid: Option<String>,
+}
+
+impl SaslExternal {
+    pub fn new() -> SaslExternal {
+        SaslExternal { authzid: None }
+    }
+}
+
+impl SaslMechanism for SaslExternal {
+    fn name(&self) -> &str {
+        "EXTERNAL"
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, LdapError> {
+        Ok(self.authzid.as_ref().map(|authzid| authzid.clone().into_bytes()))
+    }
+
+    fn step(&mut self, _challenge: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, LdapError> {
+        Err(LdapError::Sasl("unexpected challenge for EXTERNAL".to_string()))
+    }
+}
+
+fn sasl_request(mechanism: &str, credentials: Option<Vec<u8>>) -> Tag {
+    let mut creds = vec![
+        Tag::OctetString(OctetString {
+            inner: mechanism.to_string().into_bytes(),
+            .. Default::default()
+        })
+    ];
+    if let Some(credentials) = credentials {
+        creds.push(Tag::OctetString(OctetString {
+            inner: credentials,
+            .. Default::default()
+        }));
+    }
+
+    Tag::Sequence(Sequence {
+        id: 0,
+        class: Application,
+        inner: vec![
+               Tag::Integer(Integer {
+                   inner: 3,
+                   .. Default::default()
+               }),
+               Tag::OctetString(OctetString {
+                   inner: vec![],
+                   .. Default::default()
+               }),
+               Tag::Sequence(Sequence {
+                   id: 3,
+                   class: Context,
+                   inner: creds,
+               })
+        ],
+    })
+}
+
+/// Split a BindResponse into its result and the serverSaslCreds.
+fn parse_bind_response(msg: LdapMessage) -> Result<(LdapResult, Option<Vec<u8>>), LdapError> {
+    let resp = match msg {
+        LdapMessage::Once(resp) => resp,
+        LdapMessage::Stream(first, _) => return Err(decoding_error("Unexpected response received.", Some(first.tag))),
+    };
+    match construct_bind_response(resp.tag.clone()) {
+        Some((mut result, server_creds)) => {
+            result.controls = resp.controls;
+            Ok((result, server_creds))
+        },
+        None => Err(decoding_error("Invalid BindResponse received.", Some(resp.tag))),
+    }
+}
+
+fn construct_bind_response(tag: StructureTag) -> Option<(LdapResult, Option<Vec<u8>>)> {
+    let mut tags = match tag.expect_constructed() {
+        Some(tags) => tags.into_iter().peekable(),
+        None => return None,
+    };
+    let result = match parse_result_components(&mut tags) {
+        Some(result) => result,
+        None => return None,
+    };
+    let server_creds = tags.find(|t| t.class == Context && t.id == 7)
+                           .and_then(|t| t.expect_primitive());
+    Some((result, server_creds))
+}
+
+fn simple_request(dn: String, pw: String) -> Tag {
+    Tag::Sequence(Sequence {
+        id: 0,
+        class: Application,
+        inner: vec![
+               Tag::Integer(Integer {
+                   inner: 3,
+                   .. Default::default()
+               }),
+               Tag::OctetString(OctetString {
+                   inner: dn.into_bytes(),
+                   .. Default::default()
+               }),
+               Tag::OctetString(OctetString {
+                   id: 0,
+                   class: Context,
+                   inner: pw.into_bytes(),
+               })
+        ],
+    })
+}
+
 impl Ldap {
     pub fn simple_bind(&self, dn: String, pw: String) ->
-        Box<Future<Item = bool, Error = io::Error>> {
-        let req = Tag::Sequence(Sequence {
-            id: 0,
-            class: Application,
-            inner: vec![
-                   Tag::Integer(Integer {
-                       inner: 3,
-                       .. Default::default()
-                   }),
-                   Tag::OctetString(OctetString {
-                       inner: dn.into_bytes(),
-                       .. Default::default()
-                   }),
-                   Tag::OctetString(OctetString {
-                       id: 0,
-                       class: Context,
-                       inner: pw.into_bytes(),
-                   })
-            ],
-        });
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        if self.refuse_unauthenticated && pw.is_empty() && !dn.is_empty() {
+            return Box::new(future::err(LdapError::UnauthenticatedBindRefused));
+        }
 
-        let fut = self.call(req).and_then(|res|
-            match res {
-                LdapMessage::Once(Tag::StructureTag(tag)) => {
-                    if let Some(i) = tag.expect_constructed() {
-                        return Ok(i[0] == Tag::Integer(Integer {
-                            id: 10,
-                            class: Universal,
-                            inner: 0
-                        }).into_structure())
-                    } else {
-                        return Ok(false)
-                    }
+        let fut = self.call(simple_request(dn, pw)).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+
+    /// Bind anonymously (RFC 4513 section 5.1.1), e.g. to drop the
+    /// authentication state of an earlier bind.
+    pub fn anonymous_bind(&self) -> Box<Future<Item = LdapResult, Error = LdapError>> {
+        let fut = self.call(simple_request(String::new(), String::new())).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+
+    /// Bind with a DN but without a password (RFC 4513 section 5.1.2). The
+    /// session is treated as anonymous; the DN only serves e.g. for tracing.
+    pub fn unauthenticated_bind(&self, dn: String) -> Box<Future<Item = LdapResult, Error = LdapError>> {
+        let fut = self.call(simple_request(dn, String::new())).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+
+    /// Authenticate using a SASL mechanism, exchanging BindRequests with the
+    /// server for as long as it answers with saslBindInProgress.
+    ///
+    /// No other operations should be sent on the connection while the bind
+    /// is in progress.
+    pub fn sasl_bind<M>(&self, mut mechanism: M) -> Box<Future<Item = LdapResult, Error = LdapError>>
+        where M: SaslMechanism + 'static {
+        let credentials = match mechanism.initial_response() {
+            Ok(credentials) => credentials,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let ldap = self.clone();
+        let fut = future::loop_fn((mechanism, credentials), move |(mut mechanism, credentials)| {
+            let mut op = LdapOp::new(sasl_request(mechanism.name(), credentials));
+            op.security_layer = mechanism.security_layer();
+            ldap.call_op(op).and_then(parse_bind_response).and_then(move |(result, server_creds)| {
+                match result.rc {
+                    ResultCode::SaslBindInProgress => {
+                        let credentials = try!(mechanism.step(server_creds));
+                        Ok(Loop::Continue((mechanism, credentials)))
+                    },
+                    ResultCode::Success => {
+                        try!(mechanism.finish(server_creds));
+                        Ok(Loop::Break(result))
+                    },
+                    _ => Ok(Loop::Break(result)),
                 }
-                _ => unimplemented!(),
-            }
-        );
+            })
+        });
 
         Box::new(fut)
     }
@@ -0,0 +1,63 @@
+use asnom::structures::{Tag, Sequence, OctetString};
+use asnom::common::TagClass::*;
+
+use futures::Future;
+use tokio_service::Service;
+
+use error::LdapError;
+use ldap::Ldap;
+use result::{LdapResult, ResultCode, parse_result};
+
+/// Outcome of a Compare operation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompareResult {
+    /// The entry has the value asserted (compareTrue).
+    True,
+    /// The entry doesn't have the value asserted (compareFalse).
+    False,
+    /// The comparison couldn't be performed, e.g. because the entry doesn't exist.
+    Error(LdapResult),
+}
+
+impl From<LdapResult> for CompareResult {
+    fn from(res: LdapResult) -> CompareResult {
+        match res.rc {
+            ResultCode::CompareTrue => CompareResult::True,
+            ResultCode::CompareFalse => CompareResult::False,
+            _ => CompareResult::Error(res),
+        }
+    }
+}
+
+impl Ldap {
+    pub fn compare(&self, dn: String, attr: String, value: String) ->
+        Box<Future<Item = CompareResult, Error = LdapError>> {
+        let req = Tag::Sequence(Sequence {
+            id: 14,
+            class: Application,
+            inner: vec![
+                   Tag::OctetString(OctetString {
+                       inner: dn.into_bytes(),
+                       .. Default::default()
+                   }),
+                   Tag::Sequence(Sequence {
+                       inner: vec![
+                              Tag::OctetString(OctetString {
+                                  inner: attr.into_bytes(),
+                                  .. Default::default()
+                              }),
+                              Tag::OctetString(OctetString {
+                                  inner: value.into_bytes(),
+                                  .. Default::default()
+                              })
+                       ],
+                       .. Default::default()
+                   })
+            ],
+        });
+
+        let fut = self.call(req).and_then(|res| parse_result(res).map(CompareResult::from));
+
+        Box::new(fut)
+    }
+}
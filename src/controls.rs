@@ -0,0 +1,101 @@
+use asnom::structure::StructureTag;
+use asnom::structures::{Tag, Sequence, OctetString, Boolean};
+use asnom::common::TagClass::*;
+
+/// OID of the Tree Delete control.
+pub const TREE_DELETE_OID: &'static str = "1.2.840.113556.1.4.805";
+
+/// A control sent alongside a request or response in its LDAPMessage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Control {
+    pub control_type: String,
+    pub criticality: bool,
+    pub control_value: Option<Vec<u8>>,
+}
+
+impl Control {
+    pub fn new(control_type: String, criticality: bool, control_value: Option<Vec<u8>>) -> Control {
+        Control {
+            control_type: control_type,
+            criticality: criticality,
+            control_value: control_value,
+        }
+    }
+
+    /// The Tree Delete control, which makes a Delete operation remove the
+    /// entry together with all of its subordinates.
+    pub fn tree_delete() -> Control {
+        Control::new(TREE_DELETE_OID.to_string(), true, None)
+    }
+
+    fn into_tag(self) -> Tag {
+        let mut inner = vec![
+            Tag::OctetString(OctetString {
+                inner: self.control_type.into_bytes(),
+                .. Default::default()
+            })
+        ];
+        // criticality is DEFAULT FALSE and must then be omitted
+        if self.criticality {
+            inner.push(Tag::Boolean(Boolean {
+                inner: true,
+                .. Default::default()
+            }));
+        }
+        if let Some(value) = self.control_value {
+            inner.push(Tag::OctetString(OctetString {
+                inner: value,
+                .. Default::default()
+            }));
+        }
+
+        Tag::Sequence(Sequence {
+            inner: inner,
+            .. Default::default()
+        })
+    }
+}
+
+/// Build the `[0] Controls` component of an LDAPMessage.
+pub fn build_controls(controls: Vec<Control>) -> Tag {
+    Tag::Sequence(Sequence {
+        id: 0,
+        class: Context,
+        inner: controls.into_iter().map(|c| c.into_tag()).collect(),
+    })
+}
+
+/// Parse the `[0] Controls` component of a received LDAPMessage.
+pub fn parse_controls(tag: StructureTag) -> Option<Vec<Control>> {
+    let tags = match tag.match_class(Context).and_then(|t| t.match_id(0)).and_then(|t| t.expect_constructed()) {
+        Some(tags) => tags,
+        None => return None,
+    };
+
+    let mut controls = Vec::new();
+    for t in tags {
+        let mut components = match t.expect_constructed() {
+            Some(components) => components.into_iter(),
+            None => return None,
+        };
+        let control_type = match components.next()
+                                           .and_then(|t| t.expect_primitive())
+                                           .and_then(|v| String::from_utf8(v).ok()) {
+            Some(control_type) => control_type,
+            None => return None,
+        };
+        let mut control = Control::new(control_type, false, None);
+        for c in components {
+            match (c.class, c.id) {
+                // criticality
+                (Universal, 1) => control.criticality = c.expect_primitive().map(|v| v.iter().any(|&b| b != 0)).unwrap_or(false),
+                // controlValue
+                (Universal, 4) => control.control_value = c.expect_primitive(),
+                _ => return None,
+            }
+        }
+        controls.push(control);
+    }
+
+    Some(controls)
+}
@@ -0,0 +1,35 @@
+use asnom::structures::{Tag, OctetString};
+use asnom::common::TagClass::*;
+
+use futures::Future;
+
+use controls::Control;
+use error::LdapError;
+use ldap::Ldap;
+use result::{LdapResult, parse_result};
+
+impl Ldap {
+    pub fn delete(&self, dn: String) ->
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        self.delete_with_controls(dn, vec![])
+    }
+
+    /// Delete the entry and its whole subtree using the Tree Delete control.
+    pub fn delete_tree(&self, dn: String) ->
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        self.delete_with_controls(dn, vec![Control::tree_delete()])
+    }
+
+    fn delete_with_controls(&self, dn: String, controls: Vec<Control>) ->
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        let req = Tag::OctetString(OctetString {
+            id: 10,
+            class: Application,
+            inner: dn.into_bytes(),
+        });
+
+        let fut = self.call_with_controls(req, controls).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+}
@@ -0,0 +1,550 @@
+use std::io;
+use std::str;
+
+use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::{self, Rng};
+
+use bind::SaslMechanism;
+use error::LdapError;
+use layer::SecurityLayer;
+
+const CLIENT_SIGN_MAGIC: &'static [u8] = b"Digest session key to client-to-server signing key magic constant";
+const SERVER_SIGN_MAGIC: &'static [u8] = b"Digest session key to server-to-client signing key magic constant";
+const CLIENT_SEAL_MAGIC: &'static [u8] = b"Digest H(A1) to client-to-server sealing key magic constant";
+const SERVER_SEAL_MAGIC: &'static [u8] = b"Digest H(A1) to server-to-client sealing key magic constant";
+
+/// Largest protected buffer accepted from the server, sent as our maxbuf.
+const CLIENT_MAXBUF: usize = 65536;
+/// Size of the MAC and trailer added to each protected buffer.
+const LAYER_OVERHEAD: usize = 16;
+
+/// Quality of protection of a DIGEST-MD5 session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DigestQop {
+    /// Authentication only.
+    Auth,
+    /// Integrity protection of all later messages.
+    AuthInt,
+    /// Integrity protection and encryption of all later messages.
+    AuthConf,
+}
+
+impl DigestQop {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            DigestQop::Auth => "auth",
+            DigestQop::AuthInt => "auth-int",
+            DigestQop::AuthConf => "auth-conf",
+        }
+    }
+}
+
+fn md5(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Md5::default();
+    hasher.input(data);
+    hasher.result().to_vec()
+}
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Md5>::new(key);
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn digest_error(msg: &str) -> LdapError {
+    LdapError::Sasl(msg.to_string())
+}
+
+/// Split a digest-challenge into its directives, unquoting the values.
+fn parse_directives(challenge: &str) -> Option<Vec<(String, String)>> {
+    let mut directives = Vec::new();
+    let mut chars = challenge.chars().peekable();
+    loop {
+        while let Some(&c) = chars.peek() {
+            if c == ',' || c.is_whitespace() { chars.next(); } else { break; }
+        }
+        if chars.peek().is_none() {
+            return Some(directives);
+        }
+        let mut name = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some(c) => name.push(c),
+                None => return None,
+            }
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => value.push(c),
+                        None => return None,
+                    },
+                    Some(c) => value.push(c),
+                    None => return None,
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' { break; }
+                value.push(c);
+                chars.next();
+            }
+        }
+        directives.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The RC4 stream cipher, the only cipher of RFC 2831 supported here.
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s: s, i: 0, j: 0 }
+    }
+
+    fn process(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+/// Security layer of a DIGEST-MD5 session with integrity or confidentiality
+/// protection (RFC 2831 sections 2.3 and 2.4).
+struct DigestLayer {
+    send_key: Vec<u8>,
+    recv_key: Vec<u8>,
+    send_seq: u32,
+    recv_seq: u32,
+    /// Ciphers for each direction, if the session is encrypted.
+    seal: Option<(Rc4, Rc4)>,
+    /// Largest protected buffer the server accepts.
+    server_maxbuf: usize,
+}
+
+impl DigestLayer {
+    fn mac(key: &[u8], seq: u32, msg: &[u8]) -> Vec<u8> {
+        let mut input = vec![0u8; 4];
+        BigEndian::write_u32(&mut input, seq);
+        input.extend_from_slice(msg);
+        let mut mac = hmac_md5(key, &input);
+        mac.truncate(10);
+        mac
+    }
+}
+
+impl SecurityLayer for DigestLayer {
+    fn wrap(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let mac = DigestLayer::mac(&self.send_key, self.send_seq, buf);
+        let mut out = match self.seal {
+            Some((ref mut cipher, _)) => {
+                let mut sealed = buf.to_vec();
+                sealed.extend_from_slice(&mac);
+                cipher.process(&mut sealed);
+                sealed
+            },
+            None => {
+                let mut out = buf.to_vec();
+                out.extend_from_slice(&mac);
+                out
+            },
+        };
+        let mut trailer = [0u8; 6];
+        BigEndian::write_u16(&mut trailer[..2], 1);
+        BigEndian::write_u32(&mut trailer[2..], self.send_seq);
+        out.extend_from_slice(&trailer);
+        self.send_seq = self.send_seq.wrapping_add(1);
+        Ok(out)
+    }
+
+    fn unwrap(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if buf.len() < 16 {
+            return Err(invalid("DIGEST-MD5 buffer too short"));
+        }
+        let (body, trailer) = buf.split_at(buf.len() - 6);
+        if BigEndian::read_u16(&trailer[..2]) != 1 || BigEndian::read_u32(&trailer[2..]) != self.recv_seq {
+            return Err(invalid("DIGEST-MD5 buffer out of sequence"));
+        }
+        let mut body = body.to_vec();
+        if let Some((_, ref mut cipher)) = self.seal {
+            cipher.process(&mut body);
+        }
+        let mac = body.split_off(body.len() - 10);
+        if mac != DigestLayer::mac(&self.recv_key, self.recv_seq, &body) {
+            return Err(invalid("DIGEST-MD5 integrity check failed"));
+        }
+        self.recv_seq = self.recv_seq.wrapping_add(1);
+        Ok(body)
+    }
+
+    fn max_recv_size(&self) -> usize {
+        CLIENT_MAXBUF
+    }
+
+    fn max_send_size(&self) -> usize {
+        self.server_maxbuf - LAYER_OVERHEAD
+    }
+}
+
+/// Session keys derived during the exchange.
+struct Session {
+    qop: DigestQop,
+    /// Key length of the RC4 variant in use.
+    cipher_len: usize,
+    /// The server's maxbuf.
+    server_maxbuf: usize,
+    ha1: Vec<u8>,
+    /// The expected rspauth value.
+    rspauth: String,
+}
+
+/// The DIGEST-MD5 mechanism (RFC 2831).
+///
+/// By default the strongest quality of protection offered by the server is
+/// used; of the ciphers only the RC4 variants are supported.
+pub struct DigestMd5 {
+    username: String,
+    password: String,
+    /// Host part of the digest-uri, i.e. the server's host name.
+    host: String,
+    pub authzid: Option<String>,
+    pub realm: Option<String>,
+    /// Highest quality of protection to use.
+    pub max_qop: DigestQop,
+    session: Option<Session>,
+    done: bool,
+}
+
+impl DigestMd5 {
+    pub fn new(username: String, password: String, host: String) -> DigestMd5 {
+        DigestMd5 {
+            username: username,
+            password: password,
+            host: host,
+            authzid: None,
+            realm: None,
+            max_qop: DigestQop::AuthConf,
+            session: None,
+            done: false,
+        }
+    }
+
+    fn digest_response(&mut self, challenge: &[u8]) -> Result<Vec<u8>, LdapError> {
+        let mut cnonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut cnonce);
+        let digest_uri = format!("ldap/{}", self.host);
+        self.respond(challenge, &hex(&cnonce), &digest_uri)
+    }
+
+    /// The digest-response to `challenge`, with the given client nonce and
+    /// digest-uri.
+    fn respond(&mut self, challenge: &[u8], cnonce: &str, digest_uri: &str) -> Result<Vec<u8>, LdapError> {
+        let challenge = try!(str::from_utf8(challenge).map_err(|_| digest_error("invalid digest-challenge")));
+        let directives = try!(parse_directives(challenge).ok_or(digest_error("invalid digest-challenge")));
+        let get = |name: &str| directives.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.as_str());
+
+        let nonce = try!(get("nonce").ok_or(digest_error("missing nonce")));
+        if get("algorithm") != Some("md5-sess") {
+            return Err(digest_error("unsupported algorithm"));
+        }
+        let realm = self.realm.clone()
+                        .or(get("realm").map(|r| r.to_string()))
+                        .unwrap_or(String::new());
+        // The server's receive limit; it has to leave room for some data
+        let server_maxbuf = match get("maxbuf").map(|m| m.parse::<usize>()) {
+            Some(Ok(maxbuf)) if maxbuf > LAYER_OVERHEAD => maxbuf,
+            Some(_) => return Err(digest_error("invalid maxbuf")),
+            None => 65536,
+        };
+        let offered: Vec<&str> = get("qop").unwrap_or("auth").split(',').map(|q| q.trim()).collect();
+        let ciphers: Vec<&str> = get("cipher").unwrap_or("").split(',').map(|c| c.trim()).collect();
+        let cipher = ["rc4", "rc4-56", "rc4-40"].iter().find(|c| ciphers.contains(*c));
+
+        let qop = if self.max_qop >= DigestQop::AuthConf && offered.contains(&"auth-conf") && cipher.is_some() {
+            DigestQop::AuthConf
+        } else if self.max_qop >= DigestQop::AuthInt && offered.contains(&"auth-int") {
+            DigestQop::AuthInt
+        } else if offered.contains(&"auth") {
+            DigestQop::Auth
+        } else {
+            return Err(digest_error("no acceptable quality of protection offered"));
+        };
+
+        let nc = "00000001";
+
+        let mut a1 = md5(format!("{}:{}:{}", self.username, realm, self.password).as_bytes());
+        a1.extend_from_slice(format!(":{}:{}", nonce, cnonce).as_bytes());
+        if let Some(ref authzid) = self.authzid {
+            a1.extend_from_slice(format!(":{}", authzid).as_bytes());
+        }
+        let ha1 = md5(&a1);
+        let a2_suffix = if qop == DigestQop::Auth { "" } else { ":00000000000000000000000000000000" };
+        let (response, rspauth) = {
+            let kd = |a2: String| {
+                hex(&md5(format!("{}:{}:{}:{}:{}:{}", hex(&ha1), nonce, nc, cnonce, qop.as_str(), hex(&md5(a2.as_bytes())))
+                         .as_bytes()))
+            };
+            (kd(format!("AUTHENTICATE:{}{}", digest_uri, a2_suffix)), kd(format!(":{}{}", digest_uri, a2_suffix)))
+        };
+
+        let mut fields = vec![
+            format!("username={}", quote(&self.username)),
+            format!("realm={}", quote(&realm)),
+            format!("nonce={}", quote(nonce)),
+            format!("cnonce={}", quote(cnonce)),
+            format!("nc={}", nc),
+            format!("qop={}", qop.as_str()),
+            format!("digest-uri={}", quote(digest_uri)),
+            format!("response={}", response),
+            format!("maxbuf={}", CLIENT_MAXBUF),
+        ];
+        if get("charset") == Some("utf-8") {
+            fields.push("charset=utf-8".to_string());
+        }
+        let cipher_len = match (qop, cipher) {
+            (DigestQop::AuthConf, Some(cipher)) => {
+                fields.push(format!("cipher={}", cipher));
+                match *cipher {
+                    "rc4-40" => 5,
+                    "rc4-56" => 7,
+                    _ => 16,
+                }
+            },
+            _ => 0,
+        };
+        if let Some(ref authzid) = self.authzid {
+            fields.push(format!("authzid={}", quote(authzid)));
+        }
+
+        self.session = Some(Session {
+            qop: qop,
+            cipher_len: cipher_len,
+            server_maxbuf: server_maxbuf,
+            ha1: ha1,
+            rspauth: rspauth,
+        });
+        Ok(fields.join(",").into_bytes())
+    }
+
+    fn verify_rspauth(&mut self, server_creds: &[u8]) -> Result<(), LdapError> {
+        let expected = match self.session {
+            Some(ref session) => session.rspauth.clone(),
+            None => return Err(digest_error("unexpected response-auth")),
+        };
+        let rspauth = str::from_utf8(server_creds).ok()
+                                                  .and_then(parse_directives)
+                                                  .and_then(|d| d.into_iter().find(|&(ref n, _)| n == "rspauth"))
+                                                  .map(|(_, v)| v);
+        if rspauth != Some(expected) {
+            return Err(digest_error("server response-auth mismatch"));
+        }
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl SaslMechanism for DigestMd5 {
+    fn name(&self) -> &str {
+        "DIGEST-MD5"
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, LdapError> {
+        Ok(None)
+    }
+
+    fn step(&mut self, challenge: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, LdapError> {
+        let challenge = challenge.unwrap_or(vec![]);
+        if self.session.is_none() {
+            self.digest_response(&challenge).map(Some)
+        } else if !self.done {
+            // Servers which send the response-auth with saslBindInProgress
+            // expect an empty response to complete the bind
+            self.verify_rspauth(&challenge).map(|_| None)
+        } else {
+            Err(digest_error("unexpected challenge"))
+        }
+    }
+
+    fn finish(&mut self, server_creds: Option<Vec<u8>>) -> Result<(), LdapError> {
+        if self.done {
+            return Ok(());
+        }
+        match server_creds {
+            Some(ref creds) => self.verify_rspauth(creds),
+            None => Err(digest_error("missing response-auth")),
+        }
+    }
+
+    fn security_layer(&self) -> Option<Box<SecurityLayer>> {
+        let session = match self.session {
+            Some(ref session) if session.qop != DigestQop::Auth => session,
+            _ => return None,
+        };
+        let key = |magic: &[u8]| {
+            let mut input = session.ha1.clone();
+            input.extend_from_slice(magic);
+            md5(&input)
+        };
+        let seal = if session.qop == DigestQop::AuthConf {
+            let seal_key = |magic: &[u8]| {
+                let mut input = session.ha1[..session.cipher_len].to_vec();
+                input.extend_from_slice(magic);
+                md5(&input)
+            };
+            Some((Rc4::new(&seal_key(CLIENT_SEAL_MAGIC)), Rc4::new(&seal_key(SERVER_SEAL_MAGIC))))
+        } else {
+            None
+        };
+        Some(Box::new(DigestLayer {
+            send_key: key(CLIENT_SIGN_MAGIC),
+            recv_key: key(SERVER_SIGN_MAGIC),
+            send_seq: 0,
+            recv_seq: 0,
+            seal: seal,
+            server_maxbuf: session.server_maxbuf,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: &'static [u8] =
+        b"realm=\"elwood.innosoft.com\",nonce=\"OA6MG9tEQGm2hh\",qop=\"auth\",algorithm=md5-sess,charset=utf-8";
+
+    fn rfc2831_response(digest: &mut DigestMd5) -> String {
+        let response = digest.respond(CHALLENGE, "OA6MHXh6VqTrRk", "imap/elwood.innosoft.com").unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn rfc2831_example() {
+        let mut digest = DigestMd5::new("chris".to_string(), "secret".to_string(), "elwood.innosoft.com".to_string());
+        let response = rfc2831_response(&mut digest);
+        assert_eq!(response,
+                   "username=\"chris\",realm=\"elwood.innosoft.com\",nonce=\"OA6MG9tEQGm2hh\",\
+                    cnonce=\"OA6MHXh6VqTrRk\",nc=00000001,qop=auth,digest-uri=\"imap/elwood.innosoft.com\",\
+                    response=d388dad90d4bbd760a152321f2143af7,maxbuf=65536,charset=utf-8");
+        digest.finish(Some(b"rspauth=ea40f60335c427b5527b84dbabcdfffd".to_vec())).unwrap();
+        assert!(digest.security_layer().is_none());
+    }
+
+    #[test]
+    fn wrong_rspauth() {
+        let mut digest = DigestMd5::new("chris".to_string(), "secret".to_string(), "elwood.innosoft.com".to_string());
+        rfc2831_response(&mut digest);
+        assert!(digest.finish(Some(b"rspauth=00000000000000000000000000000000".to_vec())).is_err());
+    }
+
+    /// A client layer negotiated for `qop` and `cipher`, and the matching
+    /// server layer.
+    fn layers(qop: &str, cipher: &str) -> (Box<SecurityLayer>, DigestLayer) {
+        let mut digest = DigestMd5::new("chris".to_string(), "secret".to_string(), "elwood.innosoft.com".to_string());
+        let challenge = format!("realm=\"elwood.innosoft.com\",nonce=\"OA6MG9tEQGm2hh\",qop=\"{}\",cipher=\"{}\",\
+                                 algorithm=md5-sess,charset=utf-8", qop, cipher);
+        digest.respond(challenge.as_bytes(), "OA6MHXh6VqTrRk", "ldap/elwood.innosoft.com").unwrap();
+        let client = digest.security_layer().expect("no security layer");
+
+        let session = digest.session.as_ref().unwrap();
+        let key = |base: &[u8], magic: &[u8]| {
+            let mut input = base.to_vec();
+            input.extend_from_slice(magic);
+            md5(&input)
+        };
+        let seal = if session.qop == DigestQop::AuthConf {
+            let base = &session.ha1[..session.cipher_len];
+            Some((Rc4::new(&key(base, SERVER_SEAL_MAGIC)), Rc4::new(&key(base, CLIENT_SEAL_MAGIC))))
+        } else {
+            None
+        };
+        let server = DigestLayer {
+            send_key: key(&session.ha1[..], SERVER_SIGN_MAGIC),
+            recv_key: key(&session.ha1[..], CLIENT_SIGN_MAGIC),
+            send_seq: 0,
+            recv_seq: 0,
+            seal: seal,
+            server_maxbuf: CLIENT_MAXBUF,
+        };
+        (client, server)
+    }
+
+    fn round_trip(qop: &str, cipher: &str) {
+        let (mut client, mut server) = layers(qop, cipher);
+        for msg in &[&b"first request"[..], &b"second request"[..]] {
+            let wrapped = client.wrap(msg).unwrap();
+            assert_eq!(wrapped.len(), msg.len() + LAYER_OVERHEAD);
+            // Only a confidential session hides the message
+            assert_eq!(&wrapped[..msg.len()] == *msg, qop == "auth-int");
+            assert_eq!(server.unwrap(&wrapped).unwrap(), msg.to_vec());
+
+            let wrapped = server.wrap(b"response").unwrap();
+            assert_eq!(client.unwrap(&wrapped).unwrap(), b"response".to_vec());
+        }
+    }
+
+    fn out_of_sequence(qop: &str, cipher: &str) {
+        let (mut client, mut server) = layers(qop, cipher);
+        client.wrap(b"skipped").unwrap();
+        let wrapped = client.wrap(b"request").unwrap();
+        assert!(server.unwrap(&wrapped).is_err());
+    }
+
+    fn tampered(qop: &str, cipher: &str) {
+        let (mut client, mut server) = layers(qop, cipher);
+        let mut wrapped = client.wrap(b"request").unwrap();
+        wrapped[0] ^= 1;
+        assert!(server.unwrap(&wrapped).is_err());
+    }
+
+    #[test]
+    fn integrity_layer() {
+        round_trip("auth-int", "");
+        out_of_sequence("auth-int", "");
+        tampered("auth-int", "");
+    }
+
+    #[test]
+    fn confidentiality_layer() {
+        for cipher in &["rc4", "rc4-56", "rc4-40"] {
+            round_trip("auth-conf", cipher);
+            out_of_sequence("auth-conf", cipher);
+            tampered("auth-conf", cipher);
+        }
+    }
+}
+
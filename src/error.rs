@@ -0,0 +1,114 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use asnom::structure::StructureTag;
+use native_tls;
+
+use result::LdapResult;
+
+/// Error type of all fallible operations in this crate.
+#[derive(Debug)]
+pub enum LdapError {
+    /// I/O error on the connection.
+    Io(io::Error),
+    /// Error setting up or upgrading to TLS.
+    Tls(native_tls::Error),
+    /// A message received from the server couldn't be decoded.
+    Decoding {
+        description: &'static str,
+        /// The offending tag, if it could be isolated.
+        tag: Option<StructureTag>,
+    },
+    /// A search filter couldn't be parsed.
+    FilterParse(String),
+    /// `simple_bind` was called with a DN and an empty password, which would
+    /// make an unauthenticated bind (see `Ldap::refuse_unauthenticated`).
+    UnauthenticatedBindRefused,
+    /// An LDAP URL couldn't be parsed, or can't be used as requested.
+    UrlParse(String),
+    /// A SASL exchange failed on the client side, e.g. because the server
+    /// couldn't prove its identity.
+    Sasl(String),
+    /// The server returned an unsuccessful result.
+    Result(LdapResult),
+    /// No response arrived within the timeout set with `Ldap::timeout`.
+    Timeout,
+}
+
+/// Build a decoding error for a malformed server message.
+pub fn decoding_error(description: &'static str, tag: Option<StructureTag>) -> LdapError {
+    LdapError::Decoding {
+        description: description,
+        tag: tag,
+    }
+}
+
+impl fmt::Display for LdapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LdapError::Io(ref e) => write!(f, "I/O error: {}", e),
+            LdapError::Tls(ref e) => write!(f, "TLS error: {}", e),
+            LdapError::Decoding { description, tag: Some(ref tag) } =>
+                write!(f, "decoding error: {} (class {:?}, id {})", description, tag.class, tag.id),
+            LdapError::Decoding { description, tag: None } => write!(f, "decoding error: {}", description),
+            LdapError::FilterParse(ref filter) => write!(f, "invalid search filter: {}", filter),
+            LdapError::UnauthenticatedBindRefused => write!(f, "refusing unauthenticated bind with an empty password"),
+            LdapError::UrlParse(ref msg) => write!(f, "invalid LDAP URL: {}", msg),
+            LdapError::Sasl(ref msg) => write!(f, "SASL error: {}", msg),
+            LdapError::Result(ref res) => write!(f, "LDAP error: {}: {}", res.rc, res.diagnostic_message),
+            LdapError::Timeout => write!(f, "operation timed out"),
+        }
+    }
+}
+
+impl error::Error for LdapError {
+    fn description(&self) -> &str {
+        match *self {
+            LdapError::Io(_) => "I/O error",
+            LdapError::Tls(_) => "TLS error",
+            LdapError::Decoding { description, .. } => description,
+            LdapError::FilterParse(_) => "invalid search filter",
+            LdapError::UnauthenticatedBindRefused => "refusing unauthenticated bind with an empty password",
+            LdapError::UrlParse(_) => "invalid LDAP URL",
+            LdapError::Sasl(_) => "SASL error",
+            LdapError::Result(_) => "LDAP error",
+            LdapError::Timeout => "operation timed out",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            LdapError::Io(ref e) => Some(e),
+            LdapError::Tls(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LdapError {
+    /// Errors produced below the multiplexer have to travel as `io::Error`;
+    /// an `LdapError` wrapped that way is unpacked again.
+    fn from(err: io::Error) -> LdapError {
+        if err.get_ref().map(|inner| inner.is::<LdapError>()).unwrap_or(false) {
+            let inner = err.into_inner().unwrap();
+            return *inner.downcast::<LdapError>().unwrap();
+        }
+        LdapError::Io(err)
+    }
+}
+
+impl From<native_tls::Error> for LdapError {
+    fn from(err: native_tls::Error) -> LdapError {
+        LdapError::Tls(err)
+    }
+}
+
+impl From<LdapError> for io::Error {
+    fn from(err: LdapError) -> io::Error {
+        match err {
+            LdapError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use asnom::structure::StructureTag;
+use asnom::structures::{Tag, Sequence, OctetString};
+use asnom::common::TagClass::*;
+
+use futures::{future, Future, Stream};
+use tokio_service::Service;
+
+use error::{LdapError, decoding_error};
+use ldap::Ldap;
+use protocol::LdapResponse;
+use result::{LdapResult, parse_result_components};
+use service::LdapMessage;
+
+/// OID of the "Who am I?" operation (RFC 4532).
+pub const WHOAMI_OID: &'static str = "1.3.6.1.4.1.4203.1.11.3";
+
+/// Response to an extended operation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedResponse {
+    pub result: LdapResult,
+    pub name: Option<String>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl ExtendedResponse {
+    fn construct(tag: StructureTag) -> Option<ExtendedResponse> {
+        if tag.class != Application || tag.id != 24 {
+            return None;
+        }
+        let mut tags = match tag.expect_constructed() {
+            Some(tags) => tags.into_iter().peekable(),
+            None => return None,
+        };
+        let result = match parse_result_components(&mut tags) {
+            Some(result) => result,
+            None => return None,
+        };
+
+        let mut name = None;
+        let mut value = None;
+        for t in tags {
+            match (t.class, t.id) {
+                (Context, 10) => name = t.expect_primitive().and_then(|v| String::from_utf8(v).ok()),
+                (Context, 11) => value = t.expect_primitive(),
+                _ => (),
+            }
+        }
+
+        Some(ExtendedResponse {
+            result: result,
+            name: name,
+            value: value,
+        })
+    }
+}
+
+/// A typed extended operation.
+///
+/// Implementing this trait allows using extended operations not known to
+/// this crate through `Ldap::extended_op`.
+pub trait ExtendedOp {
+    type Response;
+
+    /// OID identifying the operation.
+    fn oid(&self) -> &str;
+
+    /// Encoded request value, if the operation has one.
+    fn value(&self) -> Option<Vec<u8>>;
+
+    /// Interpret the server's response to the operation.
+    fn parse_response(&self, resp: ExtendedResponse) -> Result<Self::Response, LdapError>;
+}
+
+/// "Who am I?" operation (RFC 4532), returning the authorization identity
+/// of the session, or `None` for an anonymous session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WhoAmI;
+
+impl ExtendedOp for WhoAmI {
+    type Response = Option<String>;
+
+    fn oid(&self) -> &str {
+        WHOAMI_OID
+    }
+
+    fn value(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn parse_response(&self, resp: ExtendedResponse) -> Result<Option<String>, LdapError> {
+        match resp.value {
+            Some(ref v) if !v.is_empty() => String::from_utf8(v.clone())
+                .map(Some)
+                .map_err(|_| decoding_error("Invalid authzId received.", None)),
+            _ => Ok(None),
+        }
+    }
+}
+
+pub fn parse_extended(resp: LdapResponse) -> Result<ExtendedResponse, LdapError> {
+    let LdapResponse { tag, controls } = resp;
+    match ExtendedResponse::construct(tag.clone()) {
+        Some(mut resp) => {
+            resp.result.controls = controls;
+            Ok(resp)
+        },
+        None => Err(decoding_error("Invalid ExtendedResponse received.", Some(tag))),
+    }
+}
+
+impl Ldap {
+    pub fn extended(&self, oid: String, value: Option<Vec<u8>>) ->
+        Box<Future<Item = ExtendedResponse, Error = LdapError>> {
+        let mut inner = vec![
+            Tag::OctetString(OctetString {
+                id: 0,
+                class: Context,
+                inner: oid.into_bytes(),
+            })
+        ];
+        if let Some(value) = value {
+            inner.push(Tag::OctetString(OctetString {
+                id: 1,
+                class: Context,
+                inner: value,
+            }));
+        }
+
+        let req = Tag::Sequence(Sequence {
+            id: 23,
+            class: Application,
+            inner: inner,
+        });
+
+        let fut = self.call(req).and_then(|res| -> Box<Future<Item = ExtendedResponse, Error = LdapError>> {
+            match res {
+                LdapMessage::Once(resp) => Box::new(future::result(parse_extended(resp))),
+                // Intermediate responses preceding the final response are skipped
+                LdapMessage::Stream(_, body) => Box::new(body.map_err(LdapError::from)
+                    .fold(None, |_, resp| Ok::<_, LdapError>(Some(resp)))
+                    .and_then(|last| match last {
+                        Some(resp) => parse_extended(resp),
+                        None => Err(decoding_error("Missing ExtendedResponse.", None)),
+                    })),
+            }
+        });
+
+        Box::new(fut)
+    }
+
+    pub fn extended_op<E>(&self, op: E) -> Box<Future<Item = E::Response, Error = LdapError>>
+        where E: ExtendedOp + 'static, E::Response: 'static {
+        let oid = op.oid().to_string();
+        let value = op.value();
+        let fut = self.extended(oid, value).and_then(move |resp| op.parse_response(resp));
+
+        Box::new(fut)
+    }
+}
@@ -0,0 +1,171 @@
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::Async;
+use tokio_core::io::Io;
+
+/// Size of the plaintext buffers handed to a security layer.
+const WRAP_CHUNK: usize = 4096;
+
+/// A SASL security layer (RFC 4422 section 3.7), negotiated by a bind and
+/// protecting every message exchanged after it.
+pub trait SecurityLayer {
+    /// Protect an outgoing buffer.
+    fn wrap(&mut self, buf: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Check and recover an incoming buffer.
+    fn unwrap(&mut self, buf: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Largest protected buffer the peer may send, as negotiated.
+    fn max_recv_size(&self) -> usize {
+        65536
+    }
+
+    /// Largest plaintext buffer to protect at once, so that the result fits
+    /// into what the peer accepts.
+    fn max_send_size(&self) -> usize {
+        WRAP_CHUNK
+    }
+}
+
+impl fmt::Debug for SecurityLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecurityLayer")
+    }
+}
+
+/// Connection stream which passes data through a security layer once one
+/// has been installed.
+///
+/// Protected buffers are sent with a four-octet length prefix. Incoming
+/// buffers are unwrapped as soon as they are complete, so that a read only
+/// blocks when there's no plaintext left.
+pub struct LayeredIo<T> {
+    inner: T,
+    layer: Option<Box<SecurityLayer>>,
+    read_raw: Vec<u8>,
+    read_plain: Vec<u8>,
+    write_raw: Vec<u8>,
+}
+
+impl<T> LayeredIo<T> {
+    pub fn new(inner: T) -> LayeredIo<T> {
+        LayeredIo {
+            inner: inner,
+            layer: None,
+            read_raw: Vec::new(),
+            read_plain: Vec::new(),
+            write_raw: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn set_layer(&mut self, layer: Box<SecurityLayer>) {
+        self.layer = Some(layer);
+    }
+
+    /// Unwrap all complete buffers received so far.
+    fn unwrap_buffers(&mut self) -> io::Result<()> {
+        let layer = match self.layer {
+            Some(ref mut layer) => layer,
+            None => return Ok(()),
+        };
+        while self.read_raw.len() >= 4 {
+            let len = BigEndian::read_u32(&self.read_raw[..4]) as usize;
+            // Don't buffer up whatever the peer claims to be sending
+            if len > layer.max_recv_size() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "protected buffer exceeds the negotiated size"));
+            }
+            if self.read_raw.len() < 4 + len {
+                break;
+            }
+            let plain = try!(layer.unwrap(&self.read_raw[4..4 + len]));
+            self.read_plain.extend_from_slice(&plain);
+            self.read_raw.drain(..4 + len);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Io> LayeredIo<T> {
+    /// Write out as much of the protected data as the stream accepts.
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.write_raw.is_empty() {
+            let n = try!(self.inner.write(&self.write_raw));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write protected buffer"));
+            }
+            self.write_raw.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Io> Read for LayeredIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.layer.is_none() {
+            return self.inner.read(buf);
+        }
+        let mut chunk = [0u8; WRAP_CHUNK];
+        while self.read_plain.is_empty() {
+            let n = try!(self.inner.read(&mut chunk));
+            if n == 0 {
+                return Ok(0);
+            }
+            self.read_raw.extend_from_slice(&chunk[..n]);
+            try!(self.unwrap_buffers());
+        }
+        let n = cmp::min(buf.len(), self.read_plain.len());
+        buf[..n].copy_from_slice(&self.read_plain[..n]);
+        self.read_plain.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<T: Io> Write for LayeredIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Nothing new is accepted until earlier buffers are out
+        try!(self.write_pending());
+        let (n, wrapped) = match self.layer {
+            Some(ref mut layer) => {
+                let n = cmp::min(buf.len(), cmp::min(WRAP_CHUNK, layer.max_send_size()));
+                (n, try!(layer.wrap(&buf[..n])))
+            },
+            None => return self.inner.write(buf),
+        };
+        let mut len = [0u8; 4];
+        BigEndian::write_u32(&mut len, wrapped.len() as u32);
+        self.write_raw.extend_from_slice(&len);
+        self.write_raw.extend_from_slice(&wrapped);
+        // The buffer has been taken over, so blocking doesn't matter here
+        match self.write_pending() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_pending());
+        self.inner.flush()
+    }
+}
+
+impl<T: Io> Io for LayeredIo<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        if !self.read_plain.is_empty() {
+            return Async::Ready(());
+        }
+        self.inner.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.inner.poll_write()
+    }
+}
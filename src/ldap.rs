@@ -1,81 +1,320 @@
+use std::cell::Cell;
 use std::io;
 use std::iter;
 use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use std::vec;
 
 use asnom::structures::Tag;
 use futures::{future, Future};
-use native_tls::TlsConnector;
-use tokio_core::reactor::Handle;
+use futures::future::Loop;
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_proto::util::client_proxy::ClientProxy;
-use tokio_proto::TcpClient;
+use tokio_proto::{BindClient, TcpClient};
+use tokio_proto::streaming::Message;
 use tokio_service::Service;
-use tokio_tls::proto::Client as TlsClient;
+#[cfg(unix)]
+use tokio_uds::UnixStream;
 
-use protocol::LdapProto;
-use service::{LdapMessage, TokioMessage};
+use controls::Control;
+use error::LdapError;
+use protocol::{LdapProto, LdapOp};
+use referral::ReferralChase;
+use service::{LdapMessage, TokioMessage, TokioRequest};
+use tls::TlsConfig;
+use url::{LdapUrl, UrlScheme};
 
+#[derive(Clone)]
 pub struct Ldap {
-    inner: ClientTypeMap<ClientProxy<TokioMessage, TokioMessage, io::Error>>,
+    inner: ClientTypeMap<ClientProxy<TokioRequest, TokioMessage, io::Error>>,
+    /// Whether `simple_bind` rejects an empty password with a non-empty DN.
+    pub(crate) refuse_unauthenticated: bool,
+    /// Referral chasing settings for searches, if it's enabled.
+    pub(crate) referral_chase: Option<ReferralChase>,
+    /// Credentials of the server process, for a Unix socket connection.
+    peer_credentials: Option<PeerCredentials>,
+    handle: Handle,
+    /// How long to wait for the response to a request.
+    timeout: Option<Duration>,
+    /// Whether the connection is protected by TLS, shared by all clones
+    /// since StartTLS upgrades it for all of them.
+    tls: Rc<Cell<bool>>,
+}
+
+/// Identity of the process at the other end of a Unix domain socket, as
+/// reported by the operating system.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
 }
 
 impl Ldap {
     pub fn connect(addr: &SocketAddr, handle: &Handle) ->
-        Box<Future<Item = Ldap, Error = io::Error>> {
+        Box<Future<Item = Ldap, Error = LdapError>> {
+        let handle = handle.clone();
         let ret = TcpClient::new(LdapProto)
-            .connect(addr, handle)
-            .map(|client_proxy| {
+            .connect(addr, &handle)
+            .map(move |client_proxy| {
                 let typemap = ClientTypeMap { inner: client_proxy };
-                Ldap { inner: typemap }
-            });
+                Ldap {
+                    inner: typemap,
+                    refuse_unauthenticated: true,
+                    referral_chase: None,
+                    peer_credentials: None,
+                    handle: handle,
+                    timeout: None,
+                    tls: Rc::new(Cell::new(false)),
+                }
+            })
+            .map_err(LdapError::from);
         Box::new(ret)
     }
 
     pub fn connect_ssl(addr: &str, handle: &Handle) ->
-        Box<Future<Item = Ldap, Error = io::Error>> {
-        if addr.parse::<SocketAddr>().ok().is_some() {
-            return Box::new(future::err(io::Error::new(io::ErrorKind::Other, "SSL connection must be by hostname")));
-        }
-        let sockaddr = addr.to_socket_addrs().unwrap_or(vec![].into_iter()).next();
-        if sockaddr.is_none() {
-            return Box::new(future::err(io::Error::new(io::ErrorKind::Other, "no addresses found")));
+        Box<Future<Item = Ldap, Error = LdapError>> {
+        Ldap::connect_ssl_with(addr, &TlsConfig::new(), handle)
+    }
+
+    /// Connect over TLS like `connect_ssl`, with the settings in `config`.
+    pub fn connect_ssl_with(addr: &str, config: &TlsConfig, handle: &Handle) ->
+        Box<Future<Item = Ldap, Error = LdapError>> {
+        let host = match addr.rfind(':') {
+            Some(pos) => &addr[..pos],
+            None => addr,
+        }.trim_matches(|c| c == '[' || c == ']');
+        if !config.accepts_host(host) {
+            return Box::new(future::err(LdapError::Io(io::Error::new(io::ErrorKind::Other, "SSL connection must be by hostname"))));
         }
-        let wrapper = TlsClient::new(LdapProto,
-            TlsConnector::builder().expect("tls_builder").build().expect("connector"),
-            addr.split(':').next().expect("hostname"));
-        let ret = TcpClient::new(wrapper)
-            .connect(&sockaddr.unwrap(), handle)
-            .map(|client_proxy| {
-                let typemap = ClientTypeMap { inner: client_proxy };
-                Ldap { inner: typemap }
+        let handshake = match config.handshake(host) {
+            Ok(handshake) => handshake,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let handle = handle.clone();
+        let ret = connect_tcp(addr, &handle)
+            .and_then(move |stream| handshake.connect(stream))
+            .map(move |stream| {
+                let typemap = ClientTypeMap { inner: LdapProto.bind_client(&handle, stream) };
+                Ldap {
+                    inner: typemap,
+                    refuse_unauthenticated: true,
+                    referral_chase: None,
+                    peer_credentials: None,
+                    handle: handle,
+                    timeout: None,
+                    tls: Rc::new(Cell::new(true)),
+                }
             });
         Box::new(ret)
     }
+
+    /// Connect to a server listening on the Unix domain socket at `path`.
+    ///
+    /// Servers such as slapd map a SASL EXTERNAL bind over this connection
+    /// to the identity of the client process.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P, handle: &Handle) ->
+        Box<Future<Item = Ldap, Error = LdapError>> {
+        let stream = match UnixStream::connect(path, handle) {
+            Ok(stream) => stream,
+            Err(e) => return Box::new(future::err(LdapError::from(e))),
+        };
+        let peer_credentials = stream.peer_cred().ok().map(|cred| PeerCredentials {
+            uid: cred.uid as u32,
+            gid: cred.gid as u32,
+        });
+        let typemap = ClientTypeMap { inner: LdapProto.bind_client(handle, stream) };
+        Box::new(future::ok(Ldap {
+            inner: typemap,
+            refuse_unauthenticated: true,
+            referral_chase: None,
+            peer_credentials: peer_credentials,
+            handle: handle.clone(),
+            timeout: None,
+            tls: Rc::new(Cell::new(false)),
+        }))
+    }
+
+    /// Connect to the server named by an LDAP URL, over TCP for ldap, over
+    /// TLS for ldaps and over a Unix domain socket for ldapi. A URL without
+    /// a host means localhost; ldapi URLs have to name the socket, with its
+    /// path percent-encoded in place of the host, as in
+    /// `ldapi://%2Fvar%2Frun%2Fslapd%2Fldapi/`. In `ldapi:///var/run/ldapi`
+    /// the path would be taken as the DN.
+    pub fn connect_url(url: &LdapUrl, handle: &Handle) ->
+        Box<Future<Item = Ldap, Error = LdapError>> {
+        Ldap::connect_url_with(url, &TlsConfig::new(), handle)
+    }
+
+    /// Connect to the server named by an LDAP URL like `connect_url`, using
+    /// the settings in `config` for an ldaps URL.
+    pub fn connect_url_with(url: &LdapUrl, config: &TlsConfig, handle: &Handle) ->
+        Box<Future<Item = Ldap, Error = LdapError>> {
+        if let Some(ext) = url.critical_extension() {
+            return Box::new(future::err(LdapError::UrlParse(format!("unsupported critical extension {}", ext.name))));
+        }
+        match url.scheme {
+            UrlScheme::Ldap => {
+                let handle = handle.clone();
+                Box::new(connect_tcp(&url.host_port(), &handle).map(move |stream| {
+                    let typemap = ClientTypeMap { inner: LdapProto.bind_client(&handle, stream) };
+                    Ldap {
+                        inner: typemap,
+                        refuse_unauthenticated: true,
+                        referral_chase: None,
+                        peer_credentials: None,
+                        handle: handle,
+                        timeout: None,
+                        tls: Rc::new(Cell::new(false)),
+                    }
+                }))
+            },
+            UrlScheme::Ldaps => Ldap::connect_ssl_with(&url.host_port(), config, handle),
+            #[cfg(unix)]
+            UrlScheme::Ldapi => match url.host {
+                Some(ref path) => Ldap::connect_unix(path, handle),
+                None => Box::new(future::err(LdapError::UrlParse("ldapi URL without a socket path".to_string()))),
+            },
+            #[cfg(not(unix))]
+            UrlScheme::Ldapi => Box::new(future::err(LdapError::UrlParse("ldapi URLs aren't supported".to_string()))),
+        }
+    }
+
+    /// Set whether `simple_bind` refuses to send a bind with an empty password
+    /// and a non-empty DN, which servers treat as an unauthenticated bind
+    /// (RFC 4513 section 5.1.2) instead of failing it. This is on by default;
+    /// use `unauthenticated_bind` to make such a bind deliberately.
+    pub fn refuse_unauthenticated(&mut self, refuse: bool) {
+        self.refuse_unauthenticated = refuse;
+    }
+
+    /// Follow search result references and referrals, or stop following
+    /// them with `None`. Off by default, which hands them out unchanged.
+    pub fn chase_referrals(&mut self, chase: Option<ReferralChase>) {
+        self.referral_chase = chase;
+    }
+
+    /// Fail requests with `LdapError::Timeout` if their response doesn't
+    /// arrive within `timeout`, or wait for as long as it takes with `None`,
+    /// which is the default. For a search, the timeout applies to its first
+    /// result. A request which timed out isn't abandoned, so the server may
+    /// still carry it out.
+    pub fn timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Credentials of the server process, if connected with `connect_unix`
+    /// and the platform reports them.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.peer_credentials
+    }
+
+    /// Whether the connection is protected by TLS, from the start or after
+    /// StartTLS.
+    pub fn uses_tls(&self) -> bool {
+        self.tls.get()
+    }
+
+    pub(crate) fn set_tls(&self) {
+        self.tls.set(true);
+    }
+
+    /// Send a request with the given controls attached to its LDAPMessage.
+    pub fn call_with_controls(&self, req: Tag, controls: Vec<Control>) ->
+        Box<Future<Item = LdapMessage, Error = LdapError>> {
+        self.call_op(LdapOp::with_controls(req, controls))
+    }
+
+    pub(crate) fn call_op(&self, op: LdapOp) ->
+        Box<Future<Item = LdapMessage, Error = LdapError>> {
+        let fut = Box::new(self.inner.call(op).map_err(LdapError::from));
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return fut,
+        };
+        let timer = match Timeout::new(timeout, &self.handle) {
+            Ok(timer) => timer,
+            Err(e) => return Box::new(future::err(LdapError::from(e))),
+        };
+        let timer = timer.then(|res| -> Result<LdapMessage, LdapError> {
+            match res {
+                Ok(()) => Err(LdapError::Timeout),
+                Err(e) => Err(LdapError::from(e)),
+            }
+        });
+        Box::new(fut.select(timer).map(|(msg, _)| msg).map_err(|(e, _)| e))
+    }
+
+    /// Send a request, also returning a receiver for the message ID the
+    /// request gets assigned.
+    pub(crate) fn call_tracked(&self, mut op: LdapOp) ->
+        (Box<Future<Item = LdapMessage, Error = LdapError>>, oneshot::Receiver<u64>) {
+        let (tx, rx) = oneshot::channel();
+        op.msgid_tx = Some(tx);
+        (self.call_op(op), rx)
+    }
+}
+
+/// Resolve `addr` on a separate thread, so that a slow lookup doesn't hold
+/// up the event loop, then try each of its addresses in turn until one
+/// accepts the connection.
+fn connect_tcp(addr: &str, handle: &Handle) -> Box<Future<Item = TcpStream, Error = LdapError>> {
+    let (tx, rx) = oneshot::channel();
+    let addr = addr.to_string();
+    thread::spawn(move || {
+        let _ = tx.send(addr.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()));
+    });
+    let handle = handle.clone();
+    let fut = rx
+        .map_err(|_| LdapError::Io(io::Error::new(io::ErrorKind::Other, "address lookup failed")))
+        .and_then(|addrs| addrs.map_err(LdapError::from))
+        .and_then(move |addrs| {
+            let no_addrs = io::Error::new(io::ErrorKind::Other, "no addresses found");
+            future::loop_fn((addrs.into_iter(), no_addrs), move |(mut addrs, last_err)| ->
+                Box<Future<Item = Loop<TcpStream, (vec::IntoIter<SocketAddr>, io::Error)>, Error = LdapError>> {
+                match addrs.next() {
+                    Some(addr) => Box::new(TcpStream::connect(&addr, &handle).then(move |res| match res {
+                        Ok(stream) => Ok(Loop::Break(stream)),
+                        Err(e) => Ok(Loop::Continue((addrs, e))),
+                    })),
+                    None => Box::new(future::err(LdapError::Io(last_err))),
+                }
+            })
+        });
+    Box::new(fut)
 }
 
 impl Service for Ldap {
     type Request = Tag;
     type Response = LdapMessage;
-    type Error = io::Error;
-    type Future = Box<Future<Item = LdapMessage, Error = io::Error>>;
+    type Error = LdapError;
+    type Future = Box<Future<Item = LdapMessage, Error = LdapError>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        self.inner.call(LdapMessage::Once(req))
+        self.call_op(LdapOp::new(req))
     }
 }
 
+#[derive(Clone)]
 struct ClientTypeMap<T> {
     inner: T
 }
 
 impl<T> Service for ClientTypeMap<T>
-    where T: Service<Request = TokioMessage, Response = TokioMessage, Error = io::Error>,
+    where T: Service<Request = TokioRequest, Response = TokioMessage, Error = io::Error>,
           T::Future: 'static {
-    type Request = LdapMessage;
+    type Request = LdapOp;
     type Response = LdapMessage;
     type Error = io::Error;
     type Future = Box<Future<Item = LdapMessage, Error = io::Error>>;
 
-    fn call(&self, req: LdapMessage) -> Self::Future {
-        Box::new(self.inner.call(req.into()).map(LdapMessage::from))
+    fn call(&self, req: LdapOp) -> Self::Future {
+        Box::new(self.inner.call(Message::WithoutBody(req)).map(LdapMessage::from))
     }
 }
@@ -1,13 +1,22 @@
 extern crate asnom;
 extern crate rfc4515;
 
+#[macro_use]
 extern crate futures;
 extern crate native_tls;
 extern crate tokio_core;
 extern crate tokio_proto;
 extern crate tokio_service;
 extern crate tokio_tls;
+#[cfg(unix)]
+extern crate tokio_uds;
 extern crate byteorder;
+extern crate base64;
+extern crate hmac;
+extern crate md5;
+extern crate rand;
+extern crate sha1;
+extern crate sha2;
 
 #[macro_use]
 extern crate log;
@@ -16,11 +25,41 @@ mod ldap;
 mod sync;
 mod protocol;
 mod service;
+mod controls;
+mod result;
+mod error;
 
 mod bind;
+mod scram;
+mod digest_md5;
 mod search;
+mod referral;
+mod url;
+mod add;
+mod modify;
+mod delete;
+mod modifydn;
+mod compare;
+mod unbind;
+mod abandon;
+mod extended;
+mod tls;
+mod layer;
 
-pub use ldap::Ldap;
+pub use ldap::{Ldap, PeerCredentials};
 pub use sync::LdapSync;
 
-pub use search::{Scope, DerefAliases, SearchEntry};
+pub use bind::{SaslMechanism, SaslPlain, SaslExternal};
+pub use scram::{Scram, ScramHash};
+pub use digest_md5::{DigestMd5, DigestQop};
+pub use layer::SecurityLayer;
+pub use search::{Scope, DerefAliases, SearchEntry, SearchItem, SearchOptions, SearchRequest};
+pub use referral::ReferralChase;
+pub use url::{LdapUrl, UrlExtension, UrlScheme};
+pub use tls::TlsConfig;
+pub use result::{LdapResult, ResultCode};
+pub use error::LdapError;
+pub use modify::Mod;
+pub use controls::Control;
+pub use compare::CompareResult;
+pub use extended::{ExtendedOp, ExtendedResponse, WhoAmI};
@@ -0,0 +1,83 @@
+use asnom::structures::{Tag, Sequence, Set, OctetString, Enumerated};
+use asnom::common::TagClass::*;
+
+use futures::Future;
+use tokio_service::Service;
+
+use error::LdapError;
+use ldap::Ldap;
+use result::{LdapResult, parse_result};
+
+/// A single change to an attribute, as part of a Modify operation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mod {
+    /// Add the values to the attribute, creating it if necessary.
+    Add(String, Vec<String>),
+    /// Delete the values from the attribute. An empty list of values
+    /// deletes the whole attribute.
+    Delete(String, Vec<String>),
+    /// Replace all values of the attribute. An empty list of values
+    /// deletes the attribute if it exists.
+    Replace(String, Vec<String>),
+    /// Increment the attribute by the given value (RFC 4525).
+    Increment(String, String),
+}
+
+impl Mod {
+    fn into_tag(self) -> Tag {
+        let (operation, name, values) = match self {
+            Mod::Add(name, values) => (0, name, values),
+            Mod::Delete(name, values) => (1, name, values),
+            Mod::Replace(name, values) => (2, name, values),
+            Mod::Increment(name, value) => (3, name, vec![value]),
+        };
+
+        Tag::Sequence(Sequence {
+            inner: vec![
+                   Tag::Enumerated(Enumerated {
+                       inner: operation,
+                       .. Default::default()
+                   }),
+                   Tag::Sequence(Sequence {
+                       inner: vec![
+                              Tag::OctetString(OctetString {
+                                  inner: name.into_bytes(),
+                                  .. Default::default()
+                              }),
+                              Tag::Set(Set {
+                                  inner: values.into_iter().map(|v|
+                                       Tag::OctetString(OctetString { inner: v.into_bytes(), .. Default::default() })).collect(),
+                                  .. Default::default()
+                              })
+                       ],
+                       .. Default::default()
+                   })
+            ],
+            .. Default::default()
+        })
+    }
+}
+
+impl Ldap {
+    pub fn modify(&self, dn: String, changes: Vec<Mod>) ->
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        let req = Tag::Sequence(Sequence {
+            id: 6,
+            class: Application,
+            inner: vec![
+                   Tag::OctetString(OctetString {
+                       inner: dn.into_bytes(),
+                       .. Default::default()
+                   }),
+                   Tag::Sequence(Sequence {
+                       inner: changes.into_iter().map(|m| m.into_tag()).collect(),
+                       .. Default::default()
+                   })
+            ],
+        });
+
+        let fut = self.call(req).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+}
@@ -0,0 +1,50 @@
+use asnom::structures::{Tag, Sequence, OctetString, Boolean};
+use asnom::common::TagClass::*;
+
+use futures::Future;
+use tokio_service::Service;
+
+use error::LdapError;
+use ldap::Ldap;
+use result::{LdapResult, parse_result};
+
+impl Ldap {
+    pub fn modify_dn(&self,
+                     dn: String,
+                     new_rdn: String,
+                     delete_old_rdn: bool,
+                     new_superior: Option<String>) ->
+        Box<Future<Item = LdapResult, Error = LdapError>> {
+        let mut inner = vec![
+            Tag::OctetString(OctetString {
+                inner: dn.into_bytes(),
+                .. Default::default()
+            }),
+            Tag::OctetString(OctetString {
+                inner: new_rdn.into_bytes(),
+                .. Default::default()
+            }),
+            Tag::Boolean(Boolean {
+                inner: delete_old_rdn,
+                .. Default::default()
+            }),
+        ];
+        if let Some(new_superior) = new_superior {
+            inner.push(Tag::OctetString(OctetString {
+                id: 0,
+                class: Context,
+                inner: new_superior.into_bytes(),
+            }));
+        }
+
+        let req = Tag::Sequence(Sequence {
+            id: 12,
+            class: Application,
+            inner: inner,
+        });
+
+        let fut = self.call(req).and_then(|res| parse_result(res));
+
+        Box::new(fut)
+    }
+}
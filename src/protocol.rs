@@ -1,12 +1,19 @@
 use tokio_core::io::{Io, Codec, EasyBuf, Framed};
 use std::io;
-use std::collections::HashSet;
+use std::mem;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
 
-use tokio_proto::streaming::multiplex::{Frame, ClientProto};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use tokio_proto::streaming::multiplex::{Frame, ClientProto, Transport};
+use tokio_tls::TlsStream;
 
 use asnom::common;
 use asnom::IResult;
-use asnom::structures::{Tag, Integer, Sequence, ASNTag};
+use asnom::structure::StructureTag;
+use asnom::structures::{Tag, Integer, Sequence, Null, ASNTag};
 use asnom::parse::Parser;
 use asnom::ConsumerState;
 use asnom::Move;
@@ -16,108 +23,341 @@ use asnom::Consumer;
 use asnom::parse::{parse_tag, parse_uint};
 use asnom::write;
 
-#[derive(Debug, Clone)]
+use controls::{Control, build_controls, parse_controls};
+use error::{LdapError, decoding_error};
+use result::{ResultCode, construct_result};
+use layer::{LayeredIo, SecurityLayer};
+use tls::{MaybeTls, TlsHandshake};
+
+/// An outgoing protocol operation, together with the controls which are sent
+/// alongside it in the LDAPMessage.
+///
+/// If `msgid_tx` is set, the message ID assigned to the request is sent through
+/// it once the request is handed to the transport. If `starttls` is set, the
+/// transport is upgraded to TLS when the server accepts the request. If
+/// `security_layer` is set, it's installed below the codec when the server
+/// answers the request with success. If `flow` is set, the responses to the
+/// request count towards the limit of responses waiting for their consumers,
+/// above which the transport stops reading. If `closed_tx` is set, it's dropped along with the transport,
+/// once the connection has been closed.
+#[derive(Debug)]
+pub struct LdapOp {
+    pub tag: Tag,
+    pub controls: Vec<Control>,
+    pub msgid_tx: Option<oneshot::Sender<u64>>,
+    pub starttls: Option<TlsHandshake>,
+    pub security_layer: Option<Box<SecurityLayer>>,
+    pub flow: Option<FlowControl>,
+    pub closed_tx: Option<oneshot::Sender<()>>,
+}
+
+impl LdapOp {
+    pub fn new(tag: Tag) -> LdapOp {
+        LdapOp::with_controls(tag, vec![])
+    }
+
+    pub fn with_controls(tag: Tag, controls: Vec<Control>) -> LdapOp {
+        LdapOp {
+            tag: tag,
+            controls: controls,
+            msgid_tx: None,
+            starttls: None,
+            security_layer: None,
+            flow: None,
+            closed_tx: None,
+        }
+    }
+}
+
+/// Number of undelivered responses, over all requests, above which the
+/// transport stops reading from the connection. The multiplexer buffers them
+/// in a pool shared by all requests, which holds at most 128 frames and
+/// fails the connection when it overflows.
+const FLOW_HIGH_WATER: usize = 64;
+
+#[derive(Debug)]
+struct FlowState {
+    queued: usize,
+    closed: bool,
+    /// The transport task, if it's waiting for the consumer.
+    task: Option<Task>,
+}
+
+/// Count of the responses to a request which have been read from the
+/// connection but not yet taken by the consumer.
+///
+/// The multiplexer can only buffer a few responses which haven't been taken
+/// by their consumers, so the transport stops reading once the counts of all
+/// requests add up to `FLOW_HIGH_WATER`. This is also what gives a slow
+/// consumer of a large search backpressure on the socket.
+#[derive(Clone, Debug)]
+pub struct FlowControl {
+    inner: Arc<Mutex<FlowState>>,
+}
+
+impl FlowControl {
+    pub fn new() -> FlowControl {
+        FlowControl {
+            inner: Arc::new(Mutex::new(FlowState {
+                queued: 0,
+                closed: false,
+                task: None,
+            })),
+        }
+    }
+
+    fn received(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.queued += 1;
+    }
+
+    /// Number of responses waiting for the consumer; none once it's gone.
+    fn queued(&self) -> usize {
+        let state = self.inner.lock().unwrap();
+        if state.closed { 0 } else { state.queued }
+    }
+
+    /// Register the current task to be woken when the consumer takes a
+    /// response or goes away.
+    fn park(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.task = Some(task::current());
+    }
+
+    fn wake(state: &mut FlowState) {
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Record that the consumer has taken a response.
+    pub fn consumed(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.queued = state.queued.saturating_sub(1);
+        FlowControl::wake(&mut state);
+    }
+
+    /// Record that the consumer is gone, so that nothing waits for it.
+    pub fn close(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.closed = true;
+        FlowControl::wake(&mut state);
+    }
+}
+
+/// An incoming protocol operation, together with the controls which were sent
+/// alongside it in the LDAPMessage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LdapResponse {
+    pub tag: StructureTag,
+    pub controls: Vec<Control>,
+}
+
+pub type LdapFrame = Frame<LdapResponse, LdapResponse, io::Error>;
+
+/// Codec turning LDAPMessages into multiplex frames.
+///
+/// Operations which produce several responses (search entries and references,
+/// intermediate responses) are streamed as a message with body frames;
+/// `search_seen` holds the IDs of those for which the first message has been
+/// handed out. Their final response is then sent as the last body frame.
+///
+/// `in_flight` holds the IDs of the requests which haven't received their
+/// final response yet. An abandoned operation is taken out at once, since
+/// the server may or may not send anything more for it; whatever it sends
+/// is dropped, as are messages for IDs which were never sent.
+#[derive(Debug)]
 pub struct LdapCodec {
     search_seen: HashSet<u64>,
+    in_flight: HashSet<u64>,
+    pending: VecDeque<LdapFrame>,
+}
+
+/// Split an LDAPMessage into its message ID, protocol operation and controls.
+fn split_message(tag: StructureTag) -> Option<(u64, StructureTag, Vec<Control>)> {
+    let mut tags = match tag.match_id(16u64).and_then(|x| x.expect_constructed()) {
+        Some(tags) => tags.into_iter(),
+        None => return None,
+    };
+    let msgid = tags.next()
+                    .and_then(|x| x.match_class(common::TagClass::Universal))
+                    .and_then(|x| x.match_id(2u64))
+                    .and_then(|x| x.expect_primitive())
+                    .and_then(|x| match parse_uint(x.as_slice()) {
+                        IResult::Done(_, id) => Some(id),
+                        _ => None,
+                    });
+    let protoop = tags.next();
+    let controls = match tags.next() {
+        Some(controls) => match parse_controls(controls) {
+            Some(controls) => controls,
+            None => return None,
+        },
+        None => vec![],
+    };
+
+    match (msgid, protoop) {
+        (Some(msgid), Some(protoop)) => Some((msgid, protoop, controls)),
+        _ => None,
+    }
+}
+
+impl LdapCodec {
+    /// Frame a response of an operation which may produce several of them.
+    fn stream_part(&mut self, id: u64, resp: LdapResponse) -> LdapFrame {
+        // We have already received the first of those results, so we only
+        // send a body frame.
+        if self.search_seen.contains(&id) {
+            Frame::Body {
+                id: id,
+                chunk: Some(resp),
+            }
+        } // If we haven't yet seen that operation, we need to initially send a whole message
+        else {
+            self.search_seen.insert(id);
+            Frame::Message {
+                id: id,
+                message: resp,
+                body: true,
+                solo: false,
+            }
+        }
+    }
+
+    /// Frame the final response of an operation which may have produced
+    /// several responses.
+    fn stream_end(&mut self, id: u64, resp: LdapResponse) -> LdapFrame {
+        if self.search_seen.remove(&id) {
+            self.pending.push_back(Frame::Body {
+                id: id,
+                chunk: None,
+            });
+            Frame::Body {
+                id: id,
+                chunk: Some(resp),
+            }
+        } else {
+            Frame::Message {
+                id: id,
+                message: resp,
+                body: false,
+                solo: false,
+            }
+        }
+    }
 }
 
 impl Codec for LdapCodec {
-    type In = Frame<Tag, Tag, io::Error>;
-    type Out = Frame<Tag, Tag, io::Error>;
+    type In = LdapFrame;
+    type Out = Frame<LdapOp, Tag, io::Error>;
 
     fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Self::In>, io::Error> {
-        let mut parser = Parser::new();
-        match parser.handle(Input::Element(buf.as_slice())) {
-            &ConsumerState::Done(amt, ref tag) => {
-                match amt {
-                    Move::Consume(amt) => {
-                        buf.drain_to(amt);
-
-                        let tag = tag.clone();
-                        if let Some(mut tags) = tag.match_id(16u64).and_then(|x| x.expect_constructed()) {
-                            let protoop = tags.pop().unwrap();
-                            let msgid: Vec<u8> = tags.pop().unwrap()
-                                            .match_class(common::TagClass::Universal)
-                                            .and_then(|x| x.match_id(2u64))
-                                            .and_then(|x| x.expect_primitive()).unwrap();
-                            if let IResult::Done(_, id) = parse_uint(msgid.as_slice()) {
-                                return match protoop.id {
-                                    // SearchResultEntry
-                                    4 => {
-                                        debug!("Received a search result entry");
-                                        // We have already received the first of those results, so we only
-                                        // send a body frame.
-                                        if self.search_seen.contains(&id) {
-                                            Ok(Some(Frame::Body {
-                                                id: id as u64,
-                                                chunk: Some(Tag::StructureTag(protoop)),
-                                            }))
-                                        } // If we haven't yet seen that search, we need to initially send a whole message
-                                        else {
-                                            self.search_seen.insert(id);
-                                            Ok(Some(Frame::Message {
-                                                id: id as u64,
-                                                message: Tag::StructureTag(protoop),
-                                                body: true,
-                                                solo: false,
-                                            }))
-                                        }
-                                    },
-                                    // SearchResultDone
-                                    5 => {
-                                        debug!("Received a search result done");
-                                        let seen_res_entry = self.search_seen.contains(&id);
-                                        self.search_seen.remove(&id);
-                                        if seen_res_entry {
-                                            Ok(Some(Frame::Body {
-                                                id: id as u64,
-                                                chunk: None,
-                                            }))
-                                        } else {
-                                            Ok(Some(Frame::Message {
-                                                id: id as u64,
-                                                message: Tag::StructureTag(protoop),
-                                                body: false,
-                                                solo: false,
-                                            }))
-                                        }
-                                    },
-                                    // Any other Message
-                                    _ => {
-                                        debug!("Received a tag id {}", id);
-                                        Ok(Some(Frame::Message {
-                                            id: id as u64,
-                                            message: Tag::StructureTag(protoop),
-                                            body: false,
-                                            solo: false,
-                                        }))
-                                    },
-                                }
-                            }
-                        }
+        if let Some(frame) = self.pending.pop_front() {
+            return Ok(Some(frame));
+        }
+        // Messages for operations not in progress are dropped, so keep going until
+        // there is either a frame to hand out or the buffer runs dry.
+        loop {
+            let mut parser = Parser::new();
+            let tag = match parser.handle(Input::Element(buf.as_slice())) {
+                &ConsumerState::Done(Move::Consume(amt), ref tag) => {
+                    buf.drain_to(amt);
+                    tag.clone()
+                },
+                &ConsumerState::Done(Move::Await(_), _) => return Ok(None),
+                &ConsumerState::Done(Move::Seek(_), _) =>
+                    return Err(decoding_error("Unexpected seek while parsing a message.", None).into()),
+                &ConsumerState::Continue(_) => return Ok(None),
+                &ConsumerState::Error(_e) => return Err(decoding_error("Malformed BER received.", None).into()),
+            };
 
-                        return Err(io::Error::new(io::ErrorKind::Other, "Invalid (RequestId, Tag) received."));
-                    },
-                    Move::Seek(_) => Err(io::Error::from(io::ErrorKind::Other)),
-                    Move::Await(_) => Ok(None)
-                }
-            },
-            &ConsumerState::Continue(_) => Ok(None),
-            &ConsumerState::Error(_e) => Err(io::Error::from(io::ErrorKind::Other)),
+            let (id, protoop, controls) = match split_message(tag.clone()) {
+                Some(parts) => parts,
+                None => return Err(decoding_error("Invalid (RequestId, Tag) received.", Some(tag)).into()),
+            };
+
+            if !self.in_flight.contains(&id) {
+                debug!("Dropping a message for operation {}, which isn't in progress", id);
+                continue;
+            }
+
+            let op_id = protoop.id;
+            // Anything but an entry, reference or intermediate response ends
+            // the operation
+            if op_id != 4 && op_id != 19 && op_id != 25 {
+                self.in_flight.remove(&id);
+            }
+            let resp = LdapResponse {
+                tag: protoop,
+                controls: controls,
+            };
+            return Ok(Some(match op_id {
+                // SearchResultEntry
+                4 => {
+                    debug!("Received a search result entry");
+                    self.stream_part(id, resp)
+                },
+                // SearchResultReference
+                19 => {
+                    debug!("Received a search result reference");
+                    self.stream_part(id, resp)
+                },
+                // IntermediateResponse
+                25 => {
+                    debug!("Received an intermediate response");
+                    self.stream_part(id, resp)
+                },
+                // SearchResultDone
+                5 => {
+                    debug!("Received a search result done");
+                    self.stream_end(id, resp)
+                },
+                // ExtendedResponse
+                24 => {
+                    debug!("Received an extended response");
+                    self.stream_end(id, resp)
+                },
+                // Any other Message
+                _ => {
+                    debug!("Received a tag id {}", id);
+                    Frame::Message {
+                        id: id,
+                        message: resp,
+                        body: false,
+                        solo: false,
+                    }
+                },
+            }));
         }
     }
 
     fn encode(&mut self, msg: Self::Out, into: &mut Vec<u8>) -> io::Result<()> {
         match msg {
             Frame::Message {message, id, body: _, solo: _} => {
+                let LdapOp { tag, controls, .. } = message;
+                match SoloOp::from_tag(&tag) {
+                    Some(SoloOp::Abandon(abandoned)) => {
+                        // No more frames will be handed out for the abandoned operation
+                        self.search_seen.remove(&abandoned);
+                        self.in_flight.remove(&abandoned);
+                    },
+                    Some(SoloOp::Unbind) => (),
+                    None => {
+                        self.in_flight.insert(id);
+                    },
+                }
+                let mut inner = vec![
+                    Tag::Integer(Integer {
+                        inner: id as i64,
+                        .. Default::default()
+                    }),
+                    tag,
+                ];
+                if !controls.is_empty() {
+                    inner.push(build_controls(controls));
+                }
                 let outtag = Tag::Sequence(Sequence {
-                    inner: vec![
-                        Tag::Integer(Integer {
-                            inner: id as i64,
-                            .. Default::default()
-                        }),
-                        message,
-                    ],
+                    inner: inner,
                     .. Default::default()
                 });
 
@@ -126,26 +366,444 @@ impl Codec for LdapCodec {
                 try!(write::encode_into(into, outstruct));
                 Ok(())
             },
-            _ => unimplemented!(),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "Request bodies are not supported.")),
         }
     }
 }
 
+/// Operations to which the server never sends a response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SoloOp {
+    Unbind,
+    /// Abandon, with the message ID of the abandoned operation.
+    Abandon(u64),
+}
+
+impl SoloOp {
+    fn from_tag(tag: &Tag) -> Option<SoloOp> {
+        match *tag {
+            Tag::Null(ref t) if t.class == common::TagClass::Application && t.id == 2 =>
+                Some(SoloOp::Unbind),
+            Tag::Integer(ref t) if t.class == common::TagClass::Application && t.id == 16 =>
+                Some(SoloOp::Abandon(t.inner as u64)),
+            Tag::StructureTag(ref t) if t.class == common::TagClass::Application => {
+                match t.id {
+                    2 => Some(SoloOp::Unbind),
+                    16 => t.clone().expect_primitive().and_then(|v| match parse_uint(v.as_slice()) {
+                        IResult::Done(_, id) => Some(SoloOp::Abandon(id)),
+                        _ => None,
+                    }),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Transport wrapping the framed connection.
+///
+/// The multiplexer expects a response for every request, so for operations
+/// the server doesn't answer an empty response is produced as soon as the
+/// request has been flushed. An abandoned operation is ended with an error
+/// frame, and after an Unbind the transport ends, which shuts down the
+/// connection.
+///
+/// While a StartTLS request is outstanding no other requests are sent. If the
+/// server accepts it, the TLS handshake is performed on the underlying stream
+/// before the response is handed out. Likewise, nothing is sent while a bind
+/// which negotiates a SASL security layer is outstanding, so that all later
+/// messages go through the layer.
+pub struct LdapTransport<T> {
+    inner: Framed<LayeredIo<MaybeTls<T>>, LdapCodec>,
+    unanswered: Vec<(u64, SoloOp)>,
+    responses: VecDeque<LdapFrame>,
+    unbound: bool,
+    starttls: Option<(u64, TlsHandshake)>,
+    handshake: Option<(Box<Future<Item = TlsStream<T>, Error = LdapError>>, LdapFrame)>,
+    security_layer: Option<(u64, Box<SecurityLayer>)>,
+    /// Flow control of the requests whose responses may still be waiting
+    /// for their consumers.
+    flows: HashMap<u64, FlowControl>,
+    /// IDs of the requests which haven't been answered completely.
+    outstanding: HashSet<u64>,
+    /// Senders which are dropped along with the transport.
+    closed_tx: Vec<oneshot::Sender<()>>,
+}
+
+/// Whether a response frame carries a successful LDAPResult.
+fn is_success(response: &LdapFrame) -> bool {
+    match *response {
+        Frame::Message { message: ref resp, .. } =>
+            construct_result(resp.tag.clone()).map(|res| res.rc == ResultCode::Success).unwrap_or(false),
+        _ => false,
+    }
+}
+
+impl<T: Io + 'static> LdapTransport<T> {
+    /// Start the TLS handshake if the server accepted the StartTLS request,
+    /// holding back its response until the handshake is done.
+    fn start_handshake(&mut self, starttls: TlsHandshake, response: LdapFrame) ->
+        Poll<Option<LdapFrame>, io::Error> {
+        if !is_success(&response) {
+            return Ok(Async::Ready(Some(response)));
+        }
+
+        let io = match mem::replace(self.inner.get_mut().get_mut(), MaybeTls::Upgrading) {
+            MaybeTls::Plain(io) => io,
+            other => {
+                *self.inner.get_mut().get_mut() = other;
+                return Err(io::Error::new(io::ErrorKind::Other, "Connection already uses TLS."));
+            },
+        };
+        self.handshake = Some((starttls.connect(io), response));
+        self.poll()
+    }
+
+    /// Account a frame to the flow control of its request.
+    fn track_flow(&mut self, frame: &LdapFrame) {
+        let (id, ends) = match *frame {
+            Frame::Message { id, body, .. } => (id, !body),
+            Frame::Body { id, ref chunk } => (id, chunk.is_none()),
+            Frame::Error { id, .. } => (id, true),
+        };
+        if ends {
+            // The flow is kept until the consumer has caught up
+            self.outstanding.remove(&id);
+        } else if let Some(flow) = self.flows.get(&id) {
+            flow.received();
+        }
+    }
+
+    /// Number of responses waiting for their consumers, over all requests.
+    fn queued(&mut self) -> usize {
+        let outstanding = &self.outstanding;
+        self.flows.retain(|id, flow| outstanding.contains(id) || flow.queued() > 0);
+        self.flows.values().map(|flow| flow.queued()).sum()
+    }
+}
+
+impl<T: Io + 'static> Stream for LdapTransport<T> {
+    type Item = LdapFrame;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        if let Some(frame) = self.responses.pop_front() {
+            self.track_flow(&frame);
+            return Ok(Async::Ready(Some(frame)));
+        }
+        if self.unbound {
+            return Ok(Async::Ready(None));
+        }
+        if let Some((mut handshake, response)) = self.handshake.take() {
+            match handshake.poll() {
+                Ok(Async::Ready(stream)) => {
+                    *self.inner.get_mut().get_mut() = MaybeTls::Tls(stream);
+                    // Requests held back during the upgrade can be sent now
+                    task::current().notify();
+                    return Ok(Async::Ready(Some(response)));
+                },
+                Ok(Async::NotReady) => {
+                    self.handshake = Some((handshake, response));
+                    return Ok(Async::NotReady);
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Stop reading while the consumers are falling behind
+        if self.queued() >= FLOW_HIGH_WATER {
+            for flow in self.flows.values() {
+                flow.park();
+            }
+            // A consumer may have caught up in the meantime
+            if self.queued() >= FLOW_HIGH_WATER {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        let frame = try_ready!(self.inner.poll());
+        if let Some(ref frame) = frame {
+            self.track_flow(frame);
+        }
+        let starttls_id = self.starttls.as_ref().map(|&(id, _)| id);
+        let layer_id = self.security_layer.as_ref().map(|&(id, _)| id);
+        match frame {
+            Some(frame) if Some(frame.request_id()) == starttls_id => {
+                let (_, starttls) = self.starttls.take().unwrap();
+                task::current().notify();
+                self.start_handshake(starttls, frame)
+            },
+            Some(frame) if Some(frame.request_id()) == layer_id => {
+                let (_, layer) = self.security_layer.take().unwrap();
+                if is_success(&frame) {
+                    self.inner.get_mut().set_layer(layer);
+                }
+                task::current().notify();
+                Ok(Async::Ready(Some(frame)))
+            },
+            frame => Ok(Async::Ready(frame)),
+        }
+    }
+}
+
+impl<T: Io + 'static> Sink for LdapTransport<T> {
+    type SinkItem = Frame<LdapOp, Tag, io::Error>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, mut item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        if self.starttls.is_some() || self.handshake.is_some() || self.security_layer.is_some() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let (id, solo, starttls, layer, flow, closed_tx) = match item {
+            Frame::Message { id, ref mut message, .. } => {
+                if let Some(tx) = message.msgid_tx.take() {
+                    let _ = tx.send(id);
+                }
+                (Some(id),
+                 SoloOp::from_tag(&message.tag).map(|op| (id, op)),
+                 message.starttls.take().map(|starttls| (id, starttls)),
+                 message.security_layer.take().map(|layer| (id, layer)),
+                 message.flow.take().map(|flow| (id, flow)),
+                 message.closed_tx.take())
+            },
+            _ => (None, None, None, None, None, None),
+        };
+        match try!(self.inner.start_send(item)) {
+            AsyncSink::Ready => {
+                match (solo, id) {
+                    (Some(solo), _) => self.unanswered.push(solo),
+                    (None, Some(id)) => {
+                        self.outstanding.insert(id);
+                    },
+                    (None, None) => (),
+                }
+                if let Some(tx) = closed_tx {
+                    self.closed_tx.push(tx);
+                }
+                if let Some((id, flow)) = flow {
+                    self.flows.insert(id, flow);
+                }
+                self.starttls = starttls;
+                self.security_layer = layer;
+                Ok(AsyncSink::Ready)
+            },
+            AsyncSink::NotReady(mut item) => {
+                if let Frame::Message { ref mut message, .. } = item {
+                    message.starttls = starttls.map(|(_, starttls)| starttls);
+                    message.security_layer = layer.map(|(_, layer)| layer);
+                    message.flow = flow.map(|(_, flow)| flow);
+                    message.closed_tx = closed_tx;
+                }
+                Ok(AsyncSink::NotReady(item))
+            },
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if self.handshake.is_some() {
+            return Ok(Async::NotReady);
+        }
+        try_ready!(self.inner.poll_complete());
+        if !self.unanswered.is_empty() {
+            for (id, op) in self.unanswered.drain(..) {
+                match op {
+                    SoloOp::Unbind => {
+                        // Nothing will arrive for the other operations, and
+                        // the multiplexer only shuts down once they're over
+                        for outstanding in self.outstanding.drain() {
+                            self.responses.push_back(Frame::Error {
+                                id: outstanding,
+                                error: io::Error::new(io::ErrorKind::Other, "Connection unbound."),
+                            });
+                        }
+                        self.unbound = true;
+                    },
+                    SoloOp::Abandon(abandoned) => self.responses.push_back(Frame::Error {
+                        id: abandoned,
+                        error: io::Error::new(io::ErrorKind::Other, "Operation abandoned."),
+                    }),
+                }
+                self.responses.push_back(Frame::Message {
+                    id: id,
+                    message: LdapResponse {
+                        tag: Tag::Null(Null { .. Default::default() }).into_structure(),
+                        controls: vec![],
+                    },
+                    body: false,
+                    solo: false,
+                });
+            }
+            // Make sure the multiplexer polls again to pick up the responses
+            task::current().notify();
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T: Io + 'static> Transport<LdapResponse> for LdapTransport<T> {}
+
 pub struct LdapProto;
 
 impl<T: Io + 'static> ClientProto<T> for LdapProto {
-    type Request = Tag;
+    type Request = LdapOp;
     type RequestBody = Tag;
-    type Response = Tag;
-    type ResponseBody = Tag;
+    type Response = LdapResponse;
+    type ResponseBody = LdapResponse;
     type Error = io::Error;
 
-    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`
-    type Transport = Framed<T, LdapCodec>;
+    type Transport = LdapTransport<T>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        let ldapcodec = LdapCodec { search_seen: HashSet::new() };
-        Ok(io.framed(ldapcodec))
+        let ldapcodec = LdapCodec {
+            search_seen: HashSet::new(),
+            in_flight: HashSet::new(),
+            pending: VecDeque::new(),
+        };
+        Ok(LdapTransport {
+            inner: LayeredIo::new(MaybeTls::Plain(io)).framed(ldapcodec),
+            unanswered: Vec::new(),
+            responses: VecDeque::new(),
+            unbound: false,
+            starttls: None,
+            handshake: None,
+            security_layer: None,
+            flows: HashMap::new(),
+            outstanding: HashSet::new(),
+            closed_tx: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asnom::common::TagClass::Application;
+    use asnom::structures::{Enumerated, OctetString};
+
+    fn codec(id: u64) -> LdapCodec {
+        let mut in_flight = HashSet::new();
+        in_flight.insert(id);
+        LdapCodec {
+            search_seen: HashSet::new(),
+            in_flight: in_flight,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn octet_string(value: &str) -> Tag {
+        Tag::OctetString(OctetString { inner: value.as_bytes().to_vec(), .. Default::default() })
+    }
+
+    fn message(into: &mut Vec<u8>, id: u64, protoop: Tag) {
+        let tag = Tag::Sequence(Sequence {
+            inner: vec![
+                   Tag::Integer(Integer { inner: id as i64, .. Default::default() }),
+                   protoop,
+            ],
+            .. Default::default()
+        });
+        write::encode_into(into, tag.into_structure()).unwrap();
+    }
+
+    fn reference(uri: &str) -> Tag {
+        Tag::Sequence(Sequence { id: 19, class: Application, inner: vec![octet_string(uri)] })
+    }
+
+    fn search_done() -> Tag {
+        Tag::Sequence(Sequence {
+            id: 5,
+            class: Application,
+            inner: vec![
+                   Tag::Enumerated(Enumerated { inner: 0, .. Default::default() }),
+                   octet_string(""),
+                   octet_string(""),
+            ],
+        })
+    }
+
+    fn next(codec: &mut LdapCodec, buf: &mut EasyBuf) -> LdapFrame {
+        codec.decode(buf).unwrap().expect("no frame decoded")
+    }
+
+    #[test]
+    fn reference_only_search() {
+        let mut bytes = vec![];
+        message(&mut bytes, 1, reference("ldap://a.example.com/dc=example,dc=com"));
+        message(&mut bytes, 1, search_done());
+        let mut buf = EasyBuf::from(bytes);
+        let mut codec = codec(1);
+
+        match next(&mut codec, &mut buf) {
+            Frame::Message { id: 1, ref message, body: true, .. } => assert_eq!(message.tag.id, 19),
+            _ => panic!("expected the reference as a message with a body"),
+        }
+        match next(&mut codec, &mut buf) {
+            Frame::Body { id: 1, chunk: Some(ref done) } => assert_eq!(done.tag.id, 5),
+            _ => panic!("expected the result as a body frame"),
+        }
+        match next(&mut codec, &mut buf) {
+            Frame::Body { id: 1, chunk: None } => (),
+            _ => panic!("expected the end of the body"),
+        }
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(codec.search_seen.is_empty());
+        assert!(codec.in_flight.is_empty());
+    }
+
+    #[test]
+    fn several_references() {
+        let mut bytes = vec![];
+        message(&mut bytes, 3, reference("ldap://a.example.com/"));
+        message(&mut bytes, 3, reference("ldap://b.example.com/"));
+        message(&mut bytes, 3, search_done());
+        let mut buf = EasyBuf::from(bytes);
+        let mut codec = codec(3);
+
+        match next(&mut codec, &mut buf) {
+            Frame::Message { id: 3, ref message, body: true, .. } => assert_eq!(message.tag.id, 19),
+            _ => panic!("expected the first reference as a message with a body"),
+        }
+        match next(&mut codec, &mut buf) {
+            Frame::Body { id: 3, chunk: Some(ref reference) } => assert_eq!(reference.tag.id, 19),
+            _ => panic!("expected the second reference as a body frame"),
+        }
+        match next(&mut codec, &mut buf) {
+            Frame::Body { id: 3, chunk: Some(ref done) } => assert_eq!(done.tag.id, 5),
+            _ => panic!("expected the result as a body frame"),
+        }
+        match next(&mut codec, &mut buf) {
+            Frame::Body { id: 3, chunk: None } => (),
+            _ => panic!("expected the end of the body"),
+        }
+    }
+
+    #[test]
+    fn empty_search() {
+        let mut bytes = vec![];
+        message(&mut bytes, 2, search_done());
+        let mut buf = EasyBuf::from(bytes);
+        let mut codec = codec(2);
+
+        match next(&mut codec, &mut buf) {
+            Frame::Message { id: 2, ref message, body: false, .. } => assert_eq!(message.tag.id, 5),
+            _ => panic!("expected the result as a single message"),
+        }
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn unknown_id_dropped() {
+        let mut bytes = vec![];
+        message(&mut bytes, 7, reference("ldap://a.example.com/"));
+        message(&mut bytes, 1, search_done());
+        let mut buf = EasyBuf::from(bytes);
+        let mut codec = codec(1);
+
+        match next(&mut codec, &mut buf) {
+            Frame::Message { id: 1, body: false, .. } => (),
+            _ => panic!("expected the message for the search in progress"),
+        }
     }
 }
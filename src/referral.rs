@@ -0,0 +1,218 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use futures::{future, Future, stream, Stream};
+use tokio_core::reactor::Handle;
+
+use controls::Control;
+use error::LdapError;
+use ldap::Ldap;
+use result::{LdapResult, ResultCode};
+use search::{Scope, SearchItem, SearchOptions, SearchRequest};
+use tls::TlsConfig;
+use url::{LdapUrl, UrlScheme};
+
+/// Settings for following the references and referrals returned by a search.
+///
+/// A followed reference is searched on a new connection, which is bound
+/// with the rebind callback if there is one, and anonymously otherwise. Its
+/// entries are merged into the results of the original search. References
+/// which can't be followed are handed out as they are.
+///
+/// By default, references from a TLS connection to a plain ldap URL aren't
+/// followed.
+#[derive(Clone)]
+pub struct ReferralChase {
+    handle: Handle,
+    hop_limit: usize,
+    rebind: Option<Rc<Fn(&Ldap, &LdapUrl) -> Box<Future<Item = LdapResult, Error = LdapError>>>>,
+    allow_downgrade: bool,
+    tls_config: TlsConfig,
+}
+
+impl ReferralChase {
+    pub fn new(handle: &Handle) -> ReferralChase {
+        ReferralChase {
+            handle: handle.clone(),
+            hop_limit: 5,
+            rebind: None,
+            allow_downgrade: false,
+            tls_config: TlsConfig::new(),
+        }
+    }
+
+    /// Maximum number of referrals followed in a row.
+    pub fn hop_limit(mut self, hop_limit: usize) -> ReferralChase {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    /// Bind new connections using `rebind`, which is given the URL of the
+    /// server connected to, so that it can decide which credentials, if
+    /// any, that server should get.
+    pub fn rebind<F>(mut self, rebind: F) -> ReferralChase
+        where F: Fn(&Ldap, &LdapUrl) -> Box<Future<Item = LdapResult, Error = LdapError>> + 'static {
+        self.rebind = Some(Rc::new(rebind));
+        self
+    }
+
+    /// Also follow references from a TLS connection to plain ldap URLs.
+    pub fn allow_downgrade(mut self, allow: bool) -> ReferralChase {
+        self.allow_downgrade = allow;
+        self
+    }
+
+    /// Connect to ldaps servers with the settings in `config`, e.g. the
+    /// same ones as the original connection. A server name set there is
+    /// used for every server.
+    pub fn tls_config(mut self, config: TlsConfig) -> ReferralChase {
+        self.tls_config = config;
+        self
+    }
+}
+
+/// Owned parameters of a search, to be repeated on other servers.
+#[derive(Clone)]
+pub(crate) struct ChaseSearch {
+    base: String,
+    scope: Scope,
+    filter: String,
+    attrs: Vec<String>,
+    opts: SearchOptions,
+    controls: Vec<Control>,
+}
+
+impl ChaseSearch {
+    pub(crate) fn from_request(req: &SearchRequest) -> ChaseSearch {
+        ChaseSearch {
+            base: req.base.to_string(),
+            scope: req.scope,
+            filter: req.filter.to_string(),
+            attrs: req.attrs.iter().map(|a| a.to_string()).collect(),
+            opts: req.opts,
+            controls: req.controls.clone(),
+        }
+    }
+
+    /// The search to send to the server a referral URL points to.
+    ///
+    /// A continuation reference names the part of the tree to search, which
+    /// with a one-level search is the single entry to return (RFC 4511
+    /// section 4.5.3). A referral repeats the search on another server.
+    fn redirect(&self, url: &LdapUrl, continuation: bool) -> ChaseSearch {
+        let scope = match (url.scope, continuation, self.scope) {
+            (Some(scope), _, _) => scope,
+            (None, true, Scope::SingleLevel) => Scope::BaseObject,
+            (None, _, scope) => scope,
+        };
+        ChaseSearch {
+            base: if url.dn.is_empty() { self.base.clone() } else { url.dn.clone() },
+            scope: scope,
+            filter: url.filter.clone().unwrap_or(self.filter.clone()),
+            attrs: self.attrs.clone(),
+            opts: self.opts,
+            controls: self.controls.clone(),
+        }
+    }
+
+    fn search(&self, ldap: &Ldap) -> Box<Stream<Item = SearchItem, Error = LdapError>> {
+        let attrs: Vec<&str> = self.attrs.iter().map(|a| a.as_str()).collect();
+        let req = SearchRequest::new(&self.base)
+            .scope(self.scope)
+            .filter(&self.filter)
+            .attrs(&attrs)
+            .options(self.opts)
+            .controls(self.controls.clone());
+        ldap.plain_search_stream(req)
+    }
+}
+
+type ItemStream = Box<Stream<Item = SearchItem, Error = LdapError>>;
+
+/// Follow the references and referrals in a stream of search results,
+/// received over TLS if `tls` is set.
+pub(crate) fn chase_referrals(items: ItemStream, search: ChaseSearch, chase: ReferralChase, tls: bool) -> ItemStream {
+    chase_from(items, search, chase, Rc::new(RefCell::new(HashSet::new())), tls, 0)
+}
+
+fn chase_from(items: ItemStream,
+              search: ChaseSearch,
+              chase: ReferralChase,
+              visited: Rc<RefCell<HashSet<String>>>,
+              tls: bool,
+              depth: usize) -> ItemStream {
+    let chased = items.map(move |item| -> ItemStream {
+        let (uris, continuation) = match item {
+            SearchItem::Reference(ref uris) => (uris.clone(), true),
+            SearchItem::SearchDone(ref res) if res.rc == ResultCode::Referral => (res.referrals.clone(), false),
+            _ => return Box::new(stream::once(Ok(item))),
+        };
+        follow(uris, continuation, item, &search, &chase, &visited, tls, depth)
+    });
+
+    Box::new(chased.flatten())
+}
+
+fn follow(uris: Vec<String>,
+          continuation: bool,
+          unfollowed: SearchItem,
+          search: &ChaseSearch,
+          chase: &ReferralChase,
+          visited: &Rc<RefCell<HashSet<String>>>,
+          tls: bool,
+          depth: usize) -> ItemStream {
+    if depth >= chase.hop_limit {
+        return Box::new(stream::once(Ok(unfollowed)));
+    }
+    // Any of the URIs may be used; take the first usable one not seen before
+    let target = uris.iter().filter_map(|uri| LdapUrl::parse(uri).ok()).find(|url| {
+        // A referral without a host can't be followed from here, and a
+        // socket path is only meaningful on the server which sent it
+        if url.host.is_none() || url.scheme == UrlScheme::Ldapi || url.critical_extension().is_some() {
+            return false;
+        }
+        // Don't let a server talk the client out of TLS
+        if tls && url.scheme != UrlScheme::Ldaps && !chase.allow_downgrade {
+            return false;
+        }
+        let base = if url.dn.is_empty() { &search.base } else { &url.dn };
+        let key = format!("{}/{}", url.host_port(), base).to_lowercase();
+        visited.borrow_mut().insert(key)
+    });
+    let url = match target {
+        Some(url) => url,
+        None => return Box::new(stream::once(Ok(unfollowed))),
+    };
+
+    let sub_search = search.redirect(&url, continuation);
+    let sub_tls = url.scheme == UrlScheme::Ldaps;
+    let conn = Ldap::connect_url_with(&url, &chase.tls_config, &chase.handle);
+    let rebind = chase.rebind.clone();
+    let chase = chase.clone();
+    let visited = visited.clone();
+    let fut = conn.and_then(move |ldap| -> Box<Future<Item = Ldap, Error = LdapError>> {
+        match rebind {
+            Some(rebind) => Box::new(rebind(&ldap, &url).and_then(|res| res.success()).map(move |_| ldap)),
+            None => Box::new(future::ok(ldap)),
+        }
+    }).map(move |ldap| {
+        let items = chase_from(sub_search.search(&ldap), sub_search, chase, visited, sub_tls, depth + 1);
+        if continuation {
+            // Only the result of the original search ends the stream
+            Box::new(items.filter(|item| match *item {
+                SearchItem::SearchDone(_) => false,
+                _ => true,
+            })) as ItemStream
+        } else {
+            items
+        }
+    }).then(move |res| -> Result<ItemStream, LdapError> {
+        match res {
+            Ok(items) => Ok(items),
+            Err(_) => Ok(Box::new(stream::once(Ok(unfollowed)))),
+        }
+    });
+
+    Box::new(fut.flatten_stream())
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use std::iter::Peekable;
+
+use asnom::IResult;
+use asnom::common::TagClass;
+use asnom::structure::StructureTag;
+use asnom::parse::parse_uint;
+
+use controls::Control;
+use error::{LdapError, decoding_error};
+use protocol::LdapResponse;
+use service::LdapMessage;
+
+macro_rules! result_codes {
+    ($($variant:ident = $code:expr, $name:expr;)*) => {
+        /// Result code of an LDAPResult.
+        ///
+        /// Covers the codes of RFC 4511 Appendix A, the cancel codes of RFC 3909,
+        /// the assertion (RFC 4528), proxied authorization (RFC 4370) and content
+        /// synchronization (RFC 4533) codes. Any other code is kept as `Other`;
+        /// note that the RFC 4511 code `other` (80) is `OtherError`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum ResultCode {
+            $($variant,)*
+            /// A code not known to this crate.
+            Other(u32),
+        }
+
+        impl From<u32> for ResultCode {
+            fn from(code: u32) -> ResultCode {
+                match code {
+                    $($code => ResultCode::$variant,)*
+                    code => ResultCode::Other(code),
+                }
+            }
+        }
+
+        impl From<ResultCode> for u32 {
+            fn from(rc: ResultCode) -> u32 {
+                match rc {
+                    $(ResultCode::$variant => $code,)*
+                    ResultCode::Other(code) => code,
+                }
+            }
+        }
+
+        impl fmt::Display for ResultCode {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    $(ResultCode::$variant => write!(f, "{} ({})", $name, $code),)*
+                    ResultCode::Other(code) => write!(f, "unknown ({})", code),
+                }
+            }
+        }
+    }
+}
+
+result_codes! {
+    Success = 0, "success";
+    OperationsError = 1, "operationsError";
+    ProtocolError = 2, "protocolError";
+    TimeLimitExceeded = 3, "timeLimitExceeded";
+    SizeLimitExceeded = 4, "sizeLimitExceeded";
+    CompareFalse = 5, "compareFalse";
+    CompareTrue = 6, "compareTrue";
+    AuthMethodNotSupported = 7, "authMethodNotSupported";
+    StrongerAuthRequired = 8, "strongerAuthRequired";
+    Referral = 10, "referral";
+    AdminLimitExceeded = 11, "adminLimitExceeded";
+    UnavailableCriticalExtension = 12, "unavailableCriticalExtension";
+    ConfidentialityRequired = 13, "confidentialityRequired";
+    SaslBindInProgress = 14, "saslBindInProgress";
+    NoSuchAttribute = 16, "noSuchAttribute";
+    UndefinedAttributeType = 17, "undefinedAttributeType";
+    InappropriateMatching = 18, "inappropriateMatching";
+    ConstraintViolation = 19, "constraintViolation";
+    AttributeOrValueExists = 20, "attributeOrValueExists";
+    InvalidAttributeSyntax = 21, "invalidAttributeSyntax";
+    NoSuchObject = 32, "noSuchObject";
+    AliasProblem = 33, "aliasProblem";
+    InvalidDnSyntax = 34, "invalidDNSyntax";
+    AliasDereferencingProblem = 36, "aliasDereferencingProblem";
+    InappropriateAuthentication = 48, "inappropriateAuthentication";
+    InvalidCredentials = 49, "invalidCredentials";
+    InsufficientAccessRights = 50, "insufficientAccessRights";
+    Busy = 51, "busy";
+    Unavailable = 52, "unavailable";
+    UnwillingToPerform = 53, "unwillingToPerform";
+    LoopDetect = 54, "loopDetect";
+    NamingViolation = 64, "namingViolation";
+    ObjectClassViolation = 65, "objectClassViolation";
+    NotAllowedOnNonLeaf = 66, "notAllowedOnNonLeaf";
+    NotAllowedOnRdn = 67, "notAllowedOnRDN";
+    EntryAlreadyExists = 68, "entryAlreadyExists";
+    ObjectClassModsProhibited = 69, "objectClassModsProhibited";
+    AffectsMultipleDsas = 71, "affectsMultipleDSAs";
+    OtherError = 80, "other";
+    Canceled = 118, "canceled";
+    NoSuchOperation = 119, "noSuchOperation";
+    TooLate = 120, "tooLate";
+    CannotCancel = 121, "cannotCancel";
+    AssertionFailed = 122, "assertionFailed";
+    AuthorizationDenied = 123, "authorizationDenied";
+    SyncRefreshRequired = 4096, "e-syncRefreshRequired";
+}
+
+impl ResultCode {
+    /// Whether the operation succeeded. compareFalse and compareTrue count as
+    /// success, since the comparison itself was performed.
+    pub fn is_success(&self) -> bool {
+        match *self {
+            ResultCode::Success | ResultCode::CompareFalse | ResultCode::CompareTrue => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the operation has to be continued on another server.
+    pub fn is_referral(&self) -> bool {
+        *self == ResultCode::Referral
+    }
+}
+
+/// The LDAPResult sent by the server as the response to most operations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LdapResult {
+    pub rc: ResultCode,
+    pub matched_dn: String,
+    pub diagnostic_message: String,
+    /// URIs of the servers to contact when the result is a referral.
+    pub referrals: Vec<String>,
+    /// Controls sent alongside the response.
+    pub controls: Vec<Control>,
+}
+
+impl LdapResult {
+    /// Turn an unsuccessful result into an `LdapError::Result`.
+    pub fn success(self) -> Result<LdapResult, LdapError> {
+        if self.rc.is_success() {
+            Ok(self)
+        } else {
+            Err(LdapError::Result(self))
+        }
+    }
+}
+
+/// Extract the LDAPResult from the single response message of an operation.
+pub fn parse_result(msg: LdapMessage) -> Result<LdapResult, LdapError> {
+    match msg {
+        LdapMessage::Once(resp) => result_from_response(resp),
+        LdapMessage::Stream(first, _) => Err(decoding_error("Unexpected response received.", Some(first.tag))),
+    }
+}
+
+/// Build the LdapResult of a response, including the response controls.
+pub fn result_from_response(resp: LdapResponse) -> Result<LdapResult, LdapError> {
+    let LdapResponse { tag, controls } = resp;
+    match construct_result(tag.clone()) {
+        Some(mut result) => {
+            result.controls = controls;
+            Ok(result)
+        },
+        None => Err(decoding_error("Invalid LDAPResult received.", Some(tag))),
+    }
+}
+
+/// Parse the leading LDAPResult components of a response.
+///
+/// Any components following the referral, like a bind response's
+/// serverSaslCreds, are ignored.
+pub fn construct_result(tag: StructureTag) -> Option<LdapResult> {
+    match tag.expect_constructed() {
+        Some(tags) => parse_result_components(&mut tags.into_iter().peekable()),
+        None => None,
+    }
+}
+
+/// Parse the LDAPResult components at the front of `tags`, leaving the
+/// operation-specific rest of the response in the iterator.
+pub fn parse_result_components<I: Iterator<Item = StructureTag>>(tags: &mut Peekable<I>) -> Option<LdapResult> {
+    let rc = tags.next()
+                 .and_then(|t| t.expect_primitive())
+                 .and_then(|v| match parse_uint(v.as_slice()) {
+                     IResult::Done(_, rc) => Some(ResultCode::from(rc as u32)),
+                     _ => None,
+                 });
+    let matched_dn = tags.next()
+                         .and_then(|t| t.expect_primitive())
+                         .and_then(|v| String::from_utf8(v).ok());
+    let diagnostic_message = tags.next()
+                                 .and_then(|t| t.expect_primitive())
+                                 .and_then(|v| String::from_utf8(v).ok());
+
+    let has_referrals = match tags.peek() {
+        Some(t) => t.class == TagClass::Context && t.id == 3,
+        None => false,
+    };
+    let referrals = if has_referrals {
+        tags.next().and_then(|t| t.expect_constructed()).and_then(|uris| {
+            uris.into_iter()
+                .map(|uri| uri.expect_primitive().and_then(|v| String::from_utf8(v).ok()))
+                .collect()
+        })
+    } else {
+        Some(vec![])
+    };
+
+    match (rc, matched_dn, diagnostic_message, referrals) {
+        (Some(rc), Some(matched_dn), Some(diagnostic_message), Some(referrals)) => Some(LdapResult {
+            rc: rc,
+            matched_dn: matched_dn,
+            diagnostic_message: diagnostic_message,
+            referrals: referrals,
+            controls: vec![],
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,293 @@
+use std::mem;
+use std::str;
+
+use base64;
+use hmac::{Hmac, Mac};
+use rand::{self, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use bind::SaslMechanism;
+use error::LdapError;
+
+/// Hash function underlying a SCRAM mechanism.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScramHash {
+    /// SCRAM-SHA-1 (RFC 5802).
+    Sha1,
+    /// SCRAM-SHA-256 (RFC 7677).
+    Sha256,
+}
+
+impl ScramHash {
+    fn mechanism(&self) -> &'static str {
+        match *self {
+            ScramHash::Sha1 => "SCRAM-SHA-1",
+            ScramHash::Sha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            ScramHash::Sha1 => {
+                let mut hasher = Sha1::default();
+                hasher.input(data);
+                hasher.result().to_vec()
+            },
+            ScramHash::Sha256 => {
+                let mut hasher = Sha256::default();
+                hasher.input(data);
+                hasher.result().to_vec()
+            },
+        }
+    }
+
+    fn hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match *self {
+            ScramHash::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new(key);
+                mac.input(data);
+                mac.result().code().to_vec()
+            },
+            ScramHash::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new(key);
+                mac.input(data);
+                mac.result().code().to_vec()
+            },
+        }
+    }
+
+    /// The Hi() function of RFC 5802, i.e. PBKDF2 with a single block.
+    fn hi(&self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&[0, 0, 0, 1]);
+        let mut u = self.hmac(password, &input);
+        let mut result = u.clone();
+        for _ in 1..iterations {
+            u = self.hmac(password, &u);
+            xor_into(&mut result, &u);
+        }
+        result
+    }
+}
+
+fn xor_into(acc: &mut [u8], other: &[u8]) {
+    for (a, b) in acc.iter_mut().zip(other) {
+        *a ^= *b;
+    }
+}
+
+/// Escape a username for use as a saslname.
+fn saslname(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+/// Highest iteration count accepted from the server. Each iteration costs
+/// two HMACs on the event loop, so a hostile server could otherwise stall
+/// it for hours.
+const MAX_ITERATIONS: u32 = 100000;
+
+/// The GS2 header: no channel binding, and no authorization identity.
+///
+/// Channel binding (the -PLUS mechanisms) needs the server certificate,
+/// which native-tls 0.1 doesn't give access to.
+const GS2_HEADER: &'static str = "n,,";
+
+fn scram_error(msg: &str) -> LdapError {
+    LdapError::Sasl(msg.to_string())
+}
+
+enum State {
+    Initial,
+    /// client-first-message-bare and the client nonce.
+    ClientFirst(String, String),
+    /// The expected ServerSignature.
+    ClientFinal(Vec<u8>),
+    Done,
+}
+
+/// A SCRAM mechanism (RFC 5802), authenticating with a password without
+/// sending it to the server, and verifying that the server knows it as well.
+///
+/// The password is used as given; it isn't prepared with SASLprep.
+pub struct Scram {
+    hash: ScramHash,
+    username: String,
+    password: String,
+    state: State,
+}
+
+impl Scram {
+    pub fn new(hash: ScramHash, username: String, password: String) -> Scram {
+        Scram {
+            hash: hash,
+            username: username,
+            password: password,
+            state: State::Initial,
+        }
+    }
+
+    pub fn sha1(username: String, password: String) -> Scram {
+        Scram::new(ScramHash::Sha1, username, password)
+    }
+
+    pub fn sha256(username: String, password: String) -> Scram {
+        Scram::new(ScramHash::Sha256, username, password)
+    }
+
+    /// The client-first-message, sent with the client nonce `cnonce`.
+    fn client_first(&mut self, cnonce: String) -> Vec<u8> {
+        let client_first_bare = format!("n={},r={}", saslname(&self.username), cnonce);
+        let client_first = format!("{}{}", GS2_HEADER, client_first_bare);
+        self.state = State::ClientFirst(client_first_bare, cnonce);
+        client_first.into_bytes()
+    }
+
+    fn client_final(&mut self, server_first: &[u8], client_first_bare: String, cnonce: String) ->
+        Result<Vec<u8>, LdapError> {
+        let server_first = try!(str::from_utf8(server_first)
+            .map_err(|_| scram_error("invalid server-first-message")));
+
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for attr in server_first.split(',') {
+            match attr.split_at(attr.find('=').unwrap_or(0)) {
+                ("r", value) => nonce = Some(&value[1..]),
+                ("s", value) => salt = base64::decode(&value[1..]).ok(),
+                ("i", value) => iterations = value[1..].parse::<u32>().ok(),
+                ("m", _) => return Err(scram_error("unsupported mandatory extension")),
+                _ => (),
+            }
+        }
+        let (nonce, salt, iterations) = match (nonce, salt, iterations) {
+            (Some(nonce), Some(salt), Some(iterations)) if iterations > 0 => (nonce, salt, iterations),
+            _ => return Err(scram_error("invalid server-first-message")),
+        };
+        if iterations > MAX_ITERATIONS {
+            return Err(scram_error("iteration count too high"));
+        }
+        if !nonce.starts_with(&cnonce) || nonce.len() == cnonce.len() {
+            return Err(scram_error("server nonce doesn't extend the client nonce"));
+        }
+
+        let client_final_without_proof = format!("c={},r={}", base64::encode(GS2_HEADER), nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, client_final_without_proof);
+
+        let salted_password = self.hash.hi(self.password.as_bytes(), &salt, iterations);
+        let client_key = self.hash.hmac(&salted_password, b"Client Key");
+        let stored_key = self.hash.hash(&client_key);
+        let client_signature = self.hash.hmac(&stored_key, auth_message.as_bytes());
+        let mut client_proof = client_key;
+        xor_into(&mut client_proof, &client_signature);
+
+        let server_key = self.hash.hmac(&salted_password, b"Server Key");
+        self.state = State::ClientFinal(self.hash.hmac(&server_key, auth_message.as_bytes()));
+
+        Ok(format!("{},p={}", client_final_without_proof, base64::encode(&client_proof)).into_bytes())
+    }
+
+    fn verify_server_final(&mut self, server_final: &[u8], server_signature: Vec<u8>) -> Result<(), LdapError> {
+        let server_final = try!(str::from_utf8(server_final)
+            .map_err(|_| scram_error("invalid server-final-message")));
+        if server_final.starts_with("e=") {
+            return Err(LdapError::Sasl(format!("server error: {}", &server_final[2..])));
+        }
+        let verifier = server_final.split(',')
+                                   .find(|attr| attr.starts_with("v="))
+                                   .and_then(|attr| base64::decode(&attr[2..]).ok());
+        match verifier {
+            Some(ref verifier) if *verifier == server_signature => {
+                self.state = State::Done;
+                Ok(())
+            },
+            _ => Err(scram_error("server signature mismatch")),
+        }
+    }
+}
+
+impl SaslMechanism for Scram {
+    fn name(&self) -> &str {
+        self.hash.mechanism()
+    }
+
+    fn initial_response(&mut self) -> Result<Option<Vec<u8>>, LdapError> {
+        let mut nonce = [0u8; 18];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Ok(Some(self.client_first(base64::encode(&nonce))))
+    }
+
+    fn step(&mut self, challenge: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, LdapError> {
+        let challenge = challenge.unwrap_or(vec![]);
+        match mem::replace(&mut self.state, State::Done) {
+            State::ClientFirst(client_first_bare, cnonce) =>
+                self.client_final(&challenge, client_first_bare, cnonce).map(Some),
+            // Some servers send the server-final-message with another
+            // saslBindInProgress instead of with the final result
+            State::ClientFinal(server_signature) =>
+                self.verify_server_final(&challenge, server_signature).map(|_| Some(vec![])),
+            _ => Err(scram_error("unexpected challenge")),
+        }
+    }
+
+    fn finish(&mut self, server_creds: Option<Vec<u8>>) -> Result<(), LdapError> {
+        match mem::replace(&mut self.state, State::Done) {
+            State::ClientFinal(server_signature) => match server_creds {
+                Some(server_final) => self.verify_server_final(&server_final, server_signature),
+                None => Err(scram_error("missing server-final-message")),
+            },
+            State::Done => Ok(()),
+            _ => Err(scram_error("exchange ended early")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(mut scram: Scram, cnonce: &str, client_first: &str, server_first: &str,
+                client_final: &str, server_final: &str) {
+        assert_eq!(scram.client_first(cnonce.to_string()), client_first.as_bytes());
+        let response = scram.step(Some(server_first.as_bytes().to_vec())).unwrap();
+        assert_eq!(response, Some(client_final.as_bytes().to_vec()));
+        scram.finish(Some(server_final.as_bytes().to_vec())).unwrap();
+    }
+
+    #[test]
+    fn rfc5802_sha1() {
+        exchange(Scram::sha1("user".to_string(), "pencil".to_string()),
+                 "fyko+d2lbbFgONRv9qkxdawL",
+                 "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL",
+                 "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+                 "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+                 "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=");
+    }
+
+    #[test]
+    fn rfc7677_sha256() {
+        exchange(Scram::sha256("user".to_string(), "pencil".to_string()),
+                 "rOprNGfwEbeRWgbNEkqO",
+                 "n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+                 "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                 "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                  p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+                 "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+    }
+
+    #[test]
+    fn wrong_server_signature() {
+        let mut scram = Scram::sha1("user".to_string(), "pencil".to_string());
+        scram.client_first("fyko+d2lbbFgONRv9qkxdawL".to_string());
+        scram.step(Some(b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096".to_vec())).unwrap();
+        assert!(scram.finish(Some(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_vec())).is_err());
+    }
+
+    #[test]
+    fn iteration_count_too_high() {
+        let mut scram = Scram::sha1("user".to_string(), "pencil".to_string());
+        scram.client_first("fyko+d2lbbFgONRv9qkxdawL".to_string());
+        let server_first = b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4294967295";
+        assert!(scram.step(Some(server_first.to_vec())).is_err());
+    }
+}
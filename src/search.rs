@@ -1,5 +1,6 @@
-use std::io;
+use std::cmp;
 use std::collections::HashMap;
+use std::i32;
 
 use asnom::structure::StructureTag;
 use asnom::structures::{Tag, Sequence, Integer, OctetString, Boolean};
@@ -7,11 +8,17 @@ use asnom::common::TagClass::*;
 
 use rfc4515::parse;
 
-use futures::{Future, stream, Stream};
-use tokio_service::Service;
+use futures::{Async, Future, Poll, stream, Stream};
 
+use abandon::Abandonable;
+use controls::Control;
+use error::{LdapError, decoding_error};
 use ldap::Ldap;
+use protocol::{FlowControl, LdapOp, LdapResponse};
+use referral::{ChaseSearch, chase_referrals};
+use result::{LdapResult, result_from_response};
 use service::{LdapMessage, LdapMessageStream};
+use url::LdapUrl;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Scope {
@@ -28,115 +35,397 @@ pub enum DerefAliases {
     Always            = 3,
 }
 
+/// Additional parameters of a search.
+///
+/// A search which hits the size or time limit still yields the entries
+/// received so far, with sizeLimitExceeded or timeLimitExceeded as the result
+/// code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchOptions {
+    deref: DerefAliases,
+    typesonly: bool,
+    sizelimit: u32,
+    timelimit: u32,
+}
+
+impl SearchOptions {
+    /// Options without limits, never dereferencing aliases and returning
+    /// attribute values.
+    pub fn new() -> SearchOptions {
+        SearchOptions {
+            deref: DerefAliases::Never,
+            typesonly: false,
+            sizelimit: 0,
+            timelimit: 0,
+        }
+    }
+
+    pub fn deref(mut self, deref: DerefAliases) -> SearchOptions {
+        self.deref = deref;
+        self
+    }
+
+    /// Return only attribute names, without values.
+    pub fn typesonly(mut self, typesonly: bool) -> SearchOptions {
+        self.typesonly = typesonly;
+        self
+    }
+
+    /// Maximum number of entries to return; 0 means no limit. Values above
+    /// the protocol's maxInt are clamped to it.
+    pub fn sizelimit(mut self, sizelimit: u32) -> SearchOptions {
+        self.sizelimit = cmp::min(sizelimit, i32::MAX as u32);
+        self
+    }
+
+    /// Maximum time in seconds the server may spend on the search; 0 means
+    /// no limit. Values above the protocol's maxInt are clamped to it.
+    pub fn timelimit(mut self, timelimit: u32) -> SearchOptions {
+        self.timelimit = cmp::min(timelimit, i32::MAX as u32);
+        self
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum SearchEntry {
     Reference(Vec<String>),
     Object {
         object_name: String,
+        /// Attributes whose values are all valid UTF-8.
         attributes: HashMap<String, Vec<String>>,
+        /// Attributes with a value which isn't valid UTF-8, or requested
+        /// with the ";binary" option, with their values as received.
+        bin_attributes: HashMap<String, Vec<Vec<u8>>>,
     },
 }
 
 impl SearchEntry {
-    pub fn construct(tag: Tag) -> SearchEntry {
-        match tag {
-            Tag::StructureTag(t) => {
-                match t.id {
-                    // Search Result Entry
-                    // Search Result Done (if the result set is empty)
-                    4|5 => {
-                        let mut tags = t.expect_constructed().unwrap();
-                        let attributes = tags.pop().unwrap();
-                        let object_name = tags.pop().unwrap();
-                        let object_name = String::from_utf8(object_name.expect_primitive().unwrap()).unwrap();
-
-                        let a = construct_attributes(attributes.expect_constructed().unwrap_or(vec![])).unwrap();
-
-                        SearchEntry::Object {
-                            object_name: object_name,
-                            attributes: a,
-                        }
-                    },
-                    // Search Result Reference
-                    19 => {
-                        // TODO actually handle this case
-                        SearchEntry::Reference(vec![])
-                    },
-                    _ => panic!("Search received a non-search tag!"),
-                }
-            }
-            _ => unimplemented!()
+    pub fn construct(tag: StructureTag) -> Result<SearchEntry, LdapError> {
+        match tag.id {
+            // Search Result Entry
+            4 => match construct_object(tag.clone()) {
+                Some(entry) => Ok(entry),
+                None => Err(decoding_error("Invalid SearchResultEntry received.", Some(tag))),
+            },
+            // Search Result Reference
+            19 => match construct_reference(tag.clone()) {
+                Some(uris) => Ok(SearchEntry::Reference(uris)),
+                None => Err(decoding_error("Invalid SearchResultReference received.", Some(tag))),
+            },
+            _ => Err(decoding_error("Search received a non-search tag.", Some(tag))),
         }
     }
 }
 
-fn construct_attributes(tags: Vec<StructureTag>) -> Option<HashMap<String, Vec<String>>> {
+fn construct_object(tag: StructureTag) -> Option<SearchEntry> {
+    let mut tags = match tag.expect_constructed() {
+        Some(tags) => tags,
+        None => return None,
+    };
+    let attributes = tags.pop()
+                         .and_then(|t| t.expect_constructed())
+                         .and_then(construct_attributes);
+    let object_name = tags.pop()
+                          .and_then(|t| t.expect_primitive())
+                          .and_then(|v| String::from_utf8(v).ok());
+
+    match (object_name, attributes) {
+        (Some(object_name), Some((attributes, bin_attributes))) => Some(SearchEntry::Object {
+            object_name: object_name,
+            attributes: attributes,
+            bin_attributes: bin_attributes,
+        }),
+        _ => None,
+    }
+}
+
+fn construct_reference(tag: StructureTag) -> Option<Vec<String>> {
+    tag.expect_constructed().and_then(|uris| {
+        uris.into_iter()
+            .map(|uri| uri.expect_primitive().and_then(|v| String::from_utf8(v).ok()))
+            .collect()
+    })
+}
+
+/// Whether the attribute description carries the ";binary" option.
+fn is_binary(attr: &str) -> bool {
+    attr.split(';').skip(1).any(|option| option.eq_ignore_ascii_case("binary"))
+}
+
+fn construct_attributes(tags: Vec<StructureTag>) ->
+    Option<(HashMap<String, Vec<String>>, HashMap<String, Vec<Vec<u8>>>)> {
     let mut map = HashMap::new();
+    let mut bin_map = HashMap::new();
     for tag in tags.into_iter() {
-        let mut inner = tag.expect_constructed().unwrap();
+        let mut inner = match tag.expect_constructed() {
+            Some(inner) => inner,
+            None => return None,
+        };
 
-        let values = inner.pop().unwrap();
-        let valuev = values.expect_constructed().unwrap()
-                           .into_iter()
-                           .map(|t| t.expect_primitive().unwrap())
-                           .map(|v| String::from_utf8(v).unwrap())
-                           .collect();
-        let key = inner.pop().unwrap();
-        let keystr = String::from_utf8(key.expect_primitive().unwrap()).unwrap();
+        let valuev = inner.pop()
+                          .and_then(|t| t.expect_constructed())
+                          .and_then(|values| values.into_iter()
+                                                   .map(|t| t.expect_primitive())
+                                                   .collect::<Option<Vec<Vec<u8>>>>());
+        let keystr = inner.pop()
+                          .and_then(|t| t.expect_primitive())
+                          .and_then(|v| String::from_utf8(v).ok());
 
-        map.insert(keystr, valuev);
+        let (keystr, valuev) = match (keystr, valuev) {
+            (Some(keystr), Some(valuev)) => (keystr, valuev),
+            _ => return None,
+        };
+        if is_binary(&keystr) {
+            bin_map.insert(keystr, valuev);
+            continue;
+        }
+        // An attribute only counts as text if all of its values are
+        match valuev.iter().map(|v| String::from_utf8(v.clone()).ok()).collect::<Option<Vec<String>>>() {
+            Some(strings) => map.insert(keystr, strings),
+            None => bin_map.insert(keystr, valuev),
+        };
     }
 
-    Some(map)
+    Some((map, bin_map))
 }
 
-impl Ldap {
-    pub fn search(&self,
-                    base: String,
-                    scope: Scope,
-                    deref: DerefAliases,
-                    typesonly: bool,
-                    filter: String,
-                    attrs: Vec<String>) ->
-        Box<Future<Item = Vec<SearchEntry>, Error = io::Error>> {
-        let req = Tag::Sequence(Sequence {
+/// An item of the stream returned by `Ldap::search_stream`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SearchItem {
+    Entry(SearchEntry),
+    Reference(Vec<String>),
+    /// The result of the search, which ends the stream.
+    SearchDone(LdapResult),
+}
+
+impl SearchItem {
+    fn construct(resp: LdapResponse) -> Result<SearchItem, LdapError> {
+        // SearchResultDone
+        if resp.tag.id == 5 {
+            return result_from_response(resp).map(SearchItem::SearchDone);
+        }
+        match try!(SearchEntry::construct(resp.tag)) {
+            SearchEntry::Reference(uris) => Ok(SearchItem::Reference(uris)),
+            entry => Ok(SearchItem::Entry(entry)),
+        }
+    }
+}
+
+/// Stream of the responses to a request, reporting each one taken to the
+/// flow control of the transport.
+struct FlowStream<S> {
+    inner: S,
+    flow: FlowControl,
+}
+
+impl<S: Stream<Item = LdapResponse>> Stream for FlowStream<S> {
+    type Item = LdapResponse;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<LdapResponse>, S::Error> {
+        let item = try_ready!(self.inner.poll());
+        if item.is_some() {
+            self.flow.consumed();
+        }
+        Ok(Async::Ready(item))
+    }
+}
+
+impl<S> Drop for FlowStream<S> {
+    fn drop(&mut self) {
+        self.flow.close();
+    }
+}
+
+/// A search, built up from the common case: the whole subtree below the base
+/// is searched for all entries, with all user attributes returned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchRequest<'a> {
+    pub(crate) base: &'a str,
+    pub(crate) scope: Scope,
+    pub(crate) filter: &'a str,
+    pub(crate) attrs: Vec<&'a str>,
+    pub(crate) opts: SearchOptions,
+    pub(crate) controls: Vec<Control>,
+}
+
+impl<'a> SearchRequest<'a> {
+    pub fn new(base: &'a str) -> SearchRequest<'a> {
+        SearchRequest {
+            base: base,
+            scope: Scope::WholeSubtree,
+            filter: "(objectClass=*)",
+            attrs: vec![],
+            opts: SearchOptions::new(),
+            controls: vec![],
+        }
+    }
+
+    /// The search described by the DN, attributes, scope and filter of an
+    /// LDAP URL. As in RFC 4516, a URL without a scope means a base object
+    /// search.
+    pub fn from_url(url: &'a LdapUrl) -> SearchRequest<'a> {
+        let mut req = SearchRequest::new(&url.dn).scope(url.scope.unwrap_or(Scope::BaseObject));
+        req.attrs = url.attrs.iter().map(|attr| attr.as_str()).collect();
+        if let Some(ref filter) = url.filter {
+            req.filter = filter;
+        }
+        req
+    }
+
+    pub fn scope(mut self, scope: Scope) -> SearchRequest<'a> {
+        self.scope = scope;
+        self
+    }
+
+    pub fn filter(mut self, filter: &'a str) -> SearchRequest<'a> {
+        self.filter = filter;
+        self
+    }
+
+    /// Attributes to return; none means all user attributes.
+    pub fn attrs(mut self, attrs: &[&'a str]) -> SearchRequest<'a> {
+        self.attrs = attrs.to_vec();
+        self
+    }
+
+    pub fn options(mut self, opts: SearchOptions) -> SearchRequest<'a> {
+        self.opts = opts;
+        self
+    }
+
+    /// Controls to send with the request.
+    pub fn controls(mut self, controls: Vec<Control>) -> SearchRequest<'a> {
+        self.controls = controls;
+        self
+    }
+
+    fn encode(&self) -> Result<Tag, LdapError> {
+        let filter = match parse(self.filter) {
+            Ok(filter) => filter,
+            Err(_) => return Err(LdapError::FilterParse(self.filter.to_string())),
+        };
+        Ok(Tag::Sequence(Sequence {
             id: 3,
             class: Application,
             inner: vec![
                    Tag::OctetString(OctetString {
-                       inner: base.into_bytes(),
+                       inner: self.base.as_bytes().to_vec(),
                        .. Default::default()
                    }),
                    Tag::Integer(Integer {
-                       inner: scope as i64,
+                       inner: self.scope as i64,
                        .. Default::default()
                    }),
                    Tag::Integer(Integer {
-                       inner: deref as i64,
+                       inner: self.opts.deref as i64,
                        .. Default::default()
                    }),
                    Tag::Integer(Integer {
-                       inner: 0,
+                       inner: self.opts.sizelimit as i64,
                        .. Default::default()
                    }),
                    Tag::Integer(Integer {
-                       inner: 0,
+                       inner: self.opts.timelimit as i64,
                        .. Default::default()
                    }),
                    Tag::Boolean(Boolean {
-                       inner: typesonly,
+                       inner: self.opts.typesonly,
                        .. Default::default()
                    }),
-                   parse(&filter).unwrap(),
+                   filter,
                    Tag::Sequence(Sequence {
-                       inner: attrs.into_iter().map(|s|
-                            Tag::OctetString(OctetString { inner: s.into_bytes(), ..Default::default() })).collect(),
+                       inner: self.attrs.iter().map(|s|
+                            Tag::OctetString(OctetString { inner: s.as_bytes().to_vec(), ..Default::default() })).collect(),
                        .. Default::default()
                    })
             ],
-        });
+        }))
+    }
+}
+
+impl Ldap {
+    pub fn search(&self,
+                    base: String,
+                    scope: Scope,
+                    deref: DerefAliases,
+                    typesonly: bool,
+                    filter: String,
+                    attrs: Vec<String>) ->
+        Box<Future<Item = (Vec<SearchEntry>, LdapResult), Error = LdapError>> {
+        let opts = SearchOptions::new().deref(deref).typesonly(typesonly);
+        let attrs: Vec<&str> = attrs.iter().map(|a| a.as_str()).collect();
+        self.search_with(SearchRequest::new(&base).scope(scope).filter(&filter).attrs(&attrs).options(opts))
+    }
+
+    /// Perform the search described by `req`, collecting its results.
+    pub fn search_with(&self, req: SearchRequest) ->
+        Box<Future<Item = (Vec<SearchEntry>, LdapResult), Error = LdapError>> {
+        let fut = self.search_stream_with(req)
+            .fold((vec![], None), |(mut entries, mut result), item| -> Result<_, LdapError> {
+                match item {
+                    SearchItem::Entry(entry) => entries.push(entry),
+                    SearchItem::Reference(uris) => entries.push(SearchEntry::Reference(uris)),
+                    SearchItem::SearchDone(res) => result = Some(res),
+                }
+                Ok((entries, result))
+            })
+            .and_then(|(entries, result)| match result {
+                Some(result) => Ok((entries, result)),
+                None => Err(decoding_error("Missing SearchResultDone.", None)),
+            });
+
+        Box::new(fut)
+    }
+
+    /// Search like `search`, but hand out entries and references as they
+    /// arrive instead of collecting them. The last item is the result of the
+    /// search.
+    ///
+    /// While the consumer lags behind, the connection isn't read from, so a
+    /// large search doesn't pile up in memory. Dropping the stream before the
+    /// result has arrived abandons the search.
+    pub fn search_stream(&self,
+                         base: String,
+                         scope: Scope,
+                         deref: DerefAliases,
+                         typesonly: bool,
+                         filter: String,
+                         attrs: Vec<String>) ->
+        Box<Stream<Item = SearchItem, Error = LdapError>> {
+        let opts = SearchOptions::new().deref(deref).typesonly(typesonly);
+        let attrs: Vec<&str> = attrs.iter().map(|a| a.as_str()).collect();
+        self.search_stream_with(SearchRequest::new(&base).scope(scope).filter(&filter).attrs(&attrs).options(opts))
+    }
+
+    /// Stream the results of the search described by `req`.
+    ///
+    /// If referral chasing is on, references and referrals are followed as
+    /// configured, and the entries found are merged into the stream.
+    pub fn search_stream_with(&self, req: SearchRequest) ->
+        Box<Stream<Item = SearchItem, Error = LdapError>> {
+        match self.referral_chase {
+            Some(ref chase) => {
+                let search = ChaseSearch::from_request(&req);
+                chase_referrals(self.plain_search_stream(req), search, chase.clone(), self.uses_tls())
+            },
+            None => self.plain_search_stream(req),
+        }
+    }
+
+    /// Stream the results of a search without following referrals.
+    pub(crate) fn plain_search_stream(&self, req: SearchRequest) ->
+        Box<Stream<Item = SearchItem, Error = LdapError>> {
+        let tag = match req.encode() {
+            Ok(tag) => tag,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+        let flow = FlowControl::new();
+        let mut op = LdapOp::with_controls(tag, req.controls);
+        op.flow = Some(flow.clone());
 
-        let fut = self.call(req).and_then(|res| {
+        let (fut, msgid) = self.call_tracked(op);
+        let responses = fut.map(|res| {
             let ostr = match res {
                 LdapMessage::Stream(first, body) => {
                     let fstr = stream::once(Ok(first));
@@ -147,12 +436,109 @@ impl Ldap {
                     fstr.chain(LdapMessageStream::empty())
                 },
             };
-            ostr.map(|x| SearchEntry::construct(x))
-                .collect()
-                .and_then(|x| Ok(x))
-        });
+            ostr.map_err(LdapError::from)
+        }).flatten_stream();
+        let items = FlowStream {
+            inner: responses,
+            flow: flow,
+        }.and_then(SearchItem::construct);
 
-        Box::new(fut)
+        Box::new(Abandonable::new(items, self.clone(), msgid))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asnom::structures::{ASNTag, Set};
+
+    fn octet_string(value: &[u8]) -> Tag {
+        Tag::OctetString(OctetString { inner: value.to_vec(), .. Default::default() })
+    }
+
+    fn attribute(name: &str, values: &[&[u8]]) -> Tag {
+        Tag::Sequence(Sequence {
+            inner: vec![
+                   octet_string(name.as_bytes()),
+                   Tag::Set(Set {
+                       inner: values.iter().map(|v| octet_string(v)).collect(),
+                       .. Default::default()
+                   })
+            ],
+            .. Default::default()
+        })
+    }
+
+    #[test]
+    fn binary_option() {
+        assert!(is_binary("userCertificate;binary"));
+        assert!(is_binary("userCertificate;BINARY"));
+        assert!(is_binary("cn;lang-en;binary"));
+        assert!(!is_binary("cn"));
+        assert!(!is_binary("cn;lang-en"));
+        assert!(!is_binary("binary"));
+    }
+
+    #[test]
+    fn text_and_binary_values() {
+        let (attrs, bin_attrs) = construct_attributes(vec![
+            attribute("cn", &[b"Babs Jensen", b"Barbara Jensen"]).into_structure(),
+            attribute("jpegPhoto", &[b"\xff\xd8\xff\xe0"]).into_structure(),
+            attribute("description", &[b"text", b"\x80"]).into_structure(),
+            attribute("userCertificate;binary", &[b"ascii only"]).into_structure(),
+        ]).unwrap();
+
+        assert_eq!(attrs.len(), 1);
+        assert_eq!(attrs["cn"], vec!["Babs Jensen".to_string(), "Barbara Jensen".to_string()]);
+        assert_eq!(bin_attrs.len(), 3);
+        assert_eq!(bin_attrs["jpegPhoto"], vec![b"\xff\xd8\xff\xe0".to_vec()]);
+        // One value which isn't UTF-8 makes the whole attribute binary
+        assert_eq!(bin_attrs["description"], vec![b"text".to_vec(), b"\x80".to_vec()]);
+        // ";binary" keeps values as bytes even if they're valid UTF-8
+        assert_eq!(bin_attrs["userCertificate;binary"], vec![b"ascii only".to_vec()]);
+    }
+
+    #[test]
+    fn malformed_attribute() {
+        assert!(construct_attributes(vec![octet_string(b"cn").into_structure()]).is_none());
+    }
+
+    #[test]
+    fn entry() {
+        let tag = Tag::Sequence(Sequence {
+            id: 4,
+            class: Application,
+            inner: vec![
+                   octet_string(b"uid=babs,dc=example,dc=com"),
+                   Tag::Sequence(Sequence {
+                       inner: vec![attribute("objectGUID", &[b"\x01\x9a\xff"])],
+                       .. Default::default()
+                   })
+            ],
+        }).into_structure();
+        match SearchEntry::construct(tag).unwrap() {
+            SearchEntry::Object { object_name, attributes, bin_attributes } => {
+                assert_eq!(object_name, "uid=babs,dc=example,dc=com");
+                assert!(attributes.is_empty());
+                assert_eq!(bin_attributes["objectGUID"], vec![b"\x01\x9a\xff".to_vec()]);
+            },
+            other => panic!("unexpected entry {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reference() {
+        let tag = Tag::Sequence(Sequence {
+            id: 19,
+            class: Application,
+            inner: vec![
+                   octet_string(b"ldap://a.example.com/dc=example,dc=com"),
+                   octet_string(b"ldap://b.example.com/dc=example,dc=com??sub"),
+            ],
+        }).into_structure();
+        assert_eq!(SearchEntry::construct(tag).unwrap(), SearchEntry::Reference(vec![
+            "ldap://a.example.com/dc=example,dc=com".to_string(),
+            "ldap://b.example.com/dc=example,dc=com??sub".to_string(),
+        ]));
+    }
+}
@@ -6,15 +6,17 @@ use futures::{Stream, Poll};
 
 use tokio_proto::streaming::{Body, Message};
 
+use protocol::{LdapOp, LdapResponse};
+
 #[derive(Debug)]
 pub enum LdapMessage {
-    Once(Tag),
-    Stream(Tag, LdapMessageStream),
+    Once(LdapResponse),
+    Stream(LdapResponse, LdapMessageStream),
 }
 
 #[derive(Debug)]
 pub struct LdapMessageStream {
-    inner: Body<Tag, io::Error>,
+    inner: Body<LdapResponse, io::Error>,
 }
 
 impl LdapMessageStream {
@@ -26,34 +28,23 @@ impl LdapMessageStream {
 }
 
 impl Stream for LdapMessageStream {
-    type Item = Tag;
+    type Item = LdapResponse;
     type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<Option<Tag>, io::Error> {
+    fn poll(&mut self) -> Poll<Option<LdapResponse>, io::Error> {
         self.inner.poll()
     }
 }
 
-pub type TokioMessage = Message<Tag, Body<Tag, io::Error>>;
+pub type TokioMessage = Message<LdapResponse, Body<LdapResponse, io::Error>>;
+pub type TokioRequest = Message<LdapOp, Body<Tag, io::Error>>;
 
 impl From<TokioMessage> for LdapMessage {
     fn from(src: TokioMessage) -> Self {
         match src {
-            Message::WithoutBody(tag) => LdapMessage::Once(tag),
-            Message::WithBody(tag, body) =>
-                LdapMessage::Stream(tag, LdapMessageStream { inner: body })
-        }
-    }
-}
-
-impl From<LdapMessage> for TokioMessage {
-    fn from(src: LdapMessage) -> Self {
-        match src {
-            LdapMessage::Once(tag) => Message::WithoutBody(tag),
-            LdapMessage::Stream(tag, body) => {
-                let LdapMessageStream { inner } = body;
-                Message::WithBody(tag, inner)
-            }
+            Message::WithoutBody(resp) => LdapMessage::Once(resp),
+            Message::WithBody(resp, body) =>
+                LdapMessage::Stream(resp, LdapMessageStream { inner: body })
         }
     }
 }
@@ -1,9 +1,21 @@
-use std::io;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
 
-use ldap::Ldap;
-use search::{Scope, DerefAliases, SearchEntry};
+use error::LdapError;
+use bind::SaslMechanism;
+use ldap::{Ldap, PeerCredentials};
+use search::{Scope, DerefAliases, SearchEntry, SearchRequest};
+use referral::ReferralChase;
+use tls::TlsConfig;
+use url::LdapUrl;
+use result::LdapResult;
+use modify::Mod;
+use compare::CompareResult;
+use extended::{ExtendedOp, ExtendedResponse};
 
+use native_tls::TlsConnector;
 use tokio_core::reactor::{Core, Handle};
 
 pub struct LdapSync {
@@ -12,9 +24,8 @@ pub struct LdapSync {
 }
 
 impl LdapSync {
-    pub fn connect(addr: &SocketAddr) -> Result<LdapSync, io::Error> {
-        // TODO better error handling
-        let mut core = Core::new().unwrap();
+    pub fn connect(addr: &SocketAddr) -> Result<LdapSync, LdapError> {
+        let mut core = try!(Core::new());
         let handle = core.handle();
 
         let ldapfut = Ldap::connect(addr, &handle);
@@ -23,9 +34,8 @@ impl LdapSync {
         Ok(LdapSync { inner: ldap, core: core })
     }
 
-    pub fn connect_ssl(addr: &str) -> Result<LdapSync, io::Error> {
-        // TODO better error handling
-        let mut core = Core::new().unwrap();
+    pub fn connect_ssl(addr: &str) -> Result<LdapSync, LdapError> {
+        let mut core = try!(Core::new());
         let handle = core.handle();
 
         let ldapfut = Ldap::connect_ssl(addr, &handle);
@@ -34,17 +44,153 @@ impl LdapSync {
         Ok(LdapSync { inner: ldap, core: core })
     }
 
-    pub fn simple_bind(&mut self, dn: String, pw: String) -> io::Result<bool> {
+    pub fn connect_ssl_with(addr: &str, config: &TlsConfig) -> Result<LdapSync, LdapError> {
+        let mut core = try!(Core::new());
+        let handle = core.handle();
+
+        let ldapfut = Ldap::connect_ssl_with(addr, config, &handle);
+        let ldap = try!(core.run(ldapfut));
+
+        Ok(LdapSync { inner: ldap, core: core })
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<LdapSync, LdapError> {
+        let mut core = try!(Core::new());
+        let handle = core.handle();
+
+        let ldapfut = Ldap::connect_unix(path, &handle);
+        let ldap = try!(core.run(ldapfut));
+
+        Ok(LdapSync { inner: ldap, core: core })
+    }
+
+    pub fn connect_url(url: &LdapUrl) -> Result<LdapSync, LdapError> {
+        let mut core = try!(Core::new());
+        let handle = core.handle();
+
+        let ldapfut = Ldap::connect_url(url, &handle);
+        let ldap = try!(core.run(ldapfut));
+
+        Ok(LdapSync { inner: ldap, core: core })
+    }
+
+    pub fn connect_url_with(url: &LdapUrl, config: &TlsConfig) -> Result<LdapSync, LdapError> {
+        let mut core = try!(Core::new());
+        let handle = core.handle();
+
+        let ldapfut = Ldap::connect_url_with(url, config, &handle);
+        let ldap = try!(core.run(ldapfut));
+
+        Ok(LdapSync { inner: ldap, core: core })
+    }
+
+    pub fn simple_bind(&mut self, dn: String, pw: String) -> Result<LdapResult, LdapError> {
         self.core.run(self.inner.simple_bind(dn, pw))
     }
 
+    pub fn anonymous_bind(&mut self) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.anonymous_bind())
+    }
+
+    pub fn unauthenticated_bind(&mut self, dn: String) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.unauthenticated_bind(dn))
+    }
+
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.inner.peer_credentials()
+    }
+
+    pub fn refuse_unauthenticated(&mut self, refuse: bool) {
+        self.inner.refuse_unauthenticated(refuse);
+    }
+
+    pub fn timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.timeout(timeout);
+    }
+
+    /// Handle of the event loop driving the connection, needed to set up
+    /// referral chasing.
+    pub fn handle(&self) -> Handle {
+        self.core.handle()
+    }
+
+    pub fn chase_referrals(&mut self, chase: Option<ReferralChase>) {
+        self.inner.chase_referrals(chase);
+    }
+
+    pub fn sasl_bind<M>(&mut self, mechanism: M) -> Result<LdapResult, LdapError>
+        where M: SaslMechanism + 'static {
+        self.core.run(self.inner.sasl_bind(mechanism))
+    }
+
     pub fn search(&mut self,
                   base: String,
                   scope: Scope,
                   deref: DerefAliases,
                   typesonly: bool,
                   filter: String,
-                  attrs: Vec<String>) -> io::Result<Vec<SearchEntry>> {
+                  attrs: Vec<String>) -> Result<(Vec<SearchEntry>, LdapResult), LdapError> {
         self.core.run(self.inner.search(base, scope, deref, typesonly, filter, attrs))
     }
+
+    pub fn search_with(&mut self, req: SearchRequest) -> Result<(Vec<SearchEntry>, LdapResult), LdapError> {
+        self.core.run(self.inner.search_with(req))
+    }
+
+    pub fn add(&mut self, dn: String, attributes: Vec<(String, Vec<String>)>) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.add(dn, attributes))
+    }
+
+    pub fn modify(&mut self, dn: String, changes: Vec<Mod>) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.modify(dn, changes))
+    }
+
+    pub fn delete(&mut self, dn: String) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.delete(dn))
+    }
+
+    pub fn delete_tree(&mut self, dn: String) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.delete_tree(dn))
+    }
+
+    pub fn modify_dn(&mut self,
+                     dn: String,
+                     new_rdn: String,
+                     delete_old_rdn: bool,
+                     new_superior: Option<String>) -> Result<LdapResult, LdapError> {
+        self.core.run(self.inner.modify_dn(dn, new_rdn, delete_old_rdn, new_superior))
+    }
+
+    pub fn compare(&mut self, dn: String, attr: String, value: String) -> Result<CompareResult, LdapError> {
+        self.core.run(self.inner.compare(dn, attr, value))
+    }
+
+    pub fn abandon(&mut self, msgid: u64) -> Result<(), LdapError> {
+        self.core.run(self.inner.abandon(msgid))
+    }
+
+    pub fn extended(&mut self, oid: String, value: Option<Vec<u8>>) -> Result<ExtendedResponse, LdapError> {
+        self.core.run(self.inner.extended(oid, value))
+    }
+
+    pub fn extended_op<E>(&mut self, op: E) -> Result<E::Response, LdapError>
+        where E: ExtendedOp + 'static, E::Response: 'static {
+        self.core.run(self.inner.extended_op(op))
+    }
+
+    pub fn start_tls(&mut self, connector: TlsConnector, domain: &str) -> Result<(), LdapError> {
+        self.core.run(self.inner.start_tls(connector, domain))
+    }
+
+    pub fn start_tls_with(&mut self, config: &TlsConfig, domain: &str) -> Result<(), LdapError> {
+        self.core.run(self.inner.start_tls_with(config, domain))
+    }
+}
+
+impl Drop for LdapSync {
+    fn drop(&mut self) {
+        // Nothing sensible can be done with an error at this point
+        let _ = self.core.run(self.inner.unbind());
+    }
 }
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+use asnom::structures::{Tag, Sequence, OctetString};
+use asnom::common::TagClass::*;
+
+use futures::{future, Async, Future};
+use native_tls::{Certificate, Pkcs12, TlsConnector};
+use tokio_core::io::Io;
+use tokio_tls::{TlsConnectorExt, TlsStream};
+
+use error::{LdapError, decoding_error};
+use extended::parse_extended;
+use ldap::Ldap;
+use protocol::LdapOp;
+use result::ResultCode;
+use service::LdapMessage;
+
+/// OID of the StartTLS extended operation.
+pub const STARTTLS_OID: &'static str = "1.3.6.1.4.1.1466.20037";
+
+/// Connection stream which can be upgraded to TLS after it has been set up.
+pub enum MaybeTls<T> {
+    Plain(T),
+    Tls(TlsStream<T>),
+    /// The TLS handshake is in progress and owns the plain stream.
+    Upgrading,
+}
+
+fn upgrading() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "TLS upgrade in progress")
+}
+
+impl<T: Io> Read for MaybeTls<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTls::Plain(ref mut s) => s.read(buf),
+            MaybeTls::Tls(ref mut s) => s.read(buf),
+            MaybeTls::Upgrading => Err(upgrading()),
+        }
+    }
+}
+
+impl<T: Io> Write for MaybeTls<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeTls::Plain(ref mut s) => s.write(buf),
+            MaybeTls::Tls(ref mut s) => s.write(buf),
+            MaybeTls::Upgrading => Err(upgrading()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeTls::Plain(ref mut s) => s.flush(),
+            MaybeTls::Tls(ref mut s) => s.flush(),
+            MaybeTls::Upgrading => Err(upgrading()),
+        }
+    }
+}
+
+impl<T: Io> Io for MaybeTls<T> {
+    fn poll_read(&mut self) -> Async<()> {
+        match *self {
+            MaybeTls::Plain(ref mut s) => s.poll_read(),
+            MaybeTls::Tls(ref mut s) => s.poll_read(),
+            MaybeTls::Upgrading => Async::NotReady,
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        match *self {
+            MaybeTls::Plain(ref mut s) => s.poll_write(),
+            MaybeTls::Tls(ref mut s) => s.poll_write(),
+            MaybeTls::Upgrading => Async::NotReady,
+        }
+    }
+}
+
+/// TLS settings for `Ldap::connect_ssl_with` and `Ldap::start_tls_with`.
+///
+/// By default, the server certificate is verified against the system's
+/// trusted roots and the name of the host connected to. For a test server
+/// with a self-signed certificate, trust that certificate with
+/// `root_certificate`.
+///
+/// Certificate pinning and a mode which skips verification aren't
+/// supported. tokio-tls 0.1 is built on native-tls 0.1, which doesn't give
+/// access to the server certificate, and can only skip the host name check,
+/// not the check of the certificate chain.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    root_certs: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, String)>,
+    server_name: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    /// Trust a DER-encoded CA certificate in addition to the system's roots.
+    pub fn root_certificate(mut self, der: Vec<u8>) -> TlsConfig {
+        self.root_certs.push(der);
+        self
+    }
+
+    /// Present the client certificate and key from a DER-encoded PKCS#12
+    /// archive, e.g. for a SASL EXTERNAL bind.
+    pub fn identity(mut self, pkcs12: Vec<u8>, password: String) -> TlsConfig {
+        self.identity = Some((pkcs12, password));
+        self
+    }
+
+    /// Send `name` in SNI and verify the certificate against it, instead of
+    /// the name of the host connected to.
+    pub fn server_name(mut self, name: String) -> TlsConfig {
+        self.server_name = Some(name);
+        self
+    }
+
+    /// Whether a connection to `host` can be made with these settings;
+    /// verification needs a name, not an address.
+    pub(crate) fn accepts_host(&self, host: &str) -> bool {
+        self.server_name.is_some() || host.parse::<IpAddr>().is_err()
+    }
+
+    pub(crate) fn handshake(&self, host: &str) -> Result<TlsHandshake, LdapError> {
+        let mut builder = try!(TlsConnector::builder());
+        for der in &self.root_certs {
+            try!(builder.add_root_certificate(try!(Certificate::from_der(der))));
+        }
+        if let Some((ref pkcs12, ref password)) = self.identity {
+            try!(builder.identity(try!(Pkcs12::from_der(pkcs12, password))));
+        }
+        Ok(TlsHandshake {
+            connector: try!(builder.build()),
+            domain: self.server_name.clone().unwrap_or(host.to_string()),
+        })
+    }
+}
+
+/// Parameters of a TLS handshake, for LDAPS or for upgrading the connection
+/// once the server has accepted a StartTLS request.
+pub struct TlsHandshake {
+    pub connector: TlsConnector,
+    /// Name to verify the certificate against.
+    pub domain: String,
+}
+
+impl TlsHandshake {
+    pub fn new(connector: TlsConnector, domain: &str) -> TlsHandshake {
+        TlsHandshake {
+            connector: connector,
+            domain: domain.to_string(),
+        }
+    }
+
+    /// Perform the handshake on `stream`.
+    pub fn connect<S: Io + 'static>(self, stream: S) -> Box<Future<Item = TlsStream<S>, Error = LdapError>> {
+        Box::new(self.connector.connect_async(&self.domain, stream).map_err(LdapError::from))
+    }
+}
+
+impl fmt::Debug for TlsHandshake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsHandshake")
+         .field("domain", &self.domain)
+         .finish()
+    }
+}
+
+impl Ldap {
+    /// Upgrade a plain connection to TLS using the StartTLS extended operation.
+    ///
+    /// The returned future resolves once the TLS handshake has completed.
+    /// No other requests are sent on the connection until then.
+    pub fn start_tls(&self, connector: TlsConnector, domain: &str) ->
+        Box<Future<Item = (), Error = LdapError>> {
+        self.start_tls_op(TlsHandshake::new(connector, domain))
+    }
+
+    /// Upgrade a plain connection to TLS like `start_tls`, with the settings
+    /// in `config`. `domain` is the name of the server.
+    pub fn start_tls_with(&self, config: &TlsConfig, domain: &str) ->
+        Box<Future<Item = (), Error = LdapError>> {
+        match config.handshake(domain) {
+            Ok(handshake) => self.start_tls_op(handshake),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn start_tls_op(&self, handshake: TlsHandshake) ->
+        Box<Future<Item = (), Error = LdapError>> {
+        let req = Tag::Sequence(Sequence {
+            id: 23,
+            class: Application,
+            inner: vec![
+                   Tag::OctetString(OctetString {
+                       id: 0,
+                       class: Context,
+                       inner: STARTTLS_OID.to_string().into_bytes(),
+                   })
+            ],
+        });
+
+        let mut op = LdapOp::new(req);
+        op.starttls = Some(handshake);
+
+        let ldap = self.clone();
+        let fut = self.call_op(op).and_then(move |res| {
+            let resp = match res {
+                LdapMessage::Once(resp) => try!(parse_extended(resp)),
+                LdapMessage::Stream(first, _) => return Err(decoding_error("Unexpected response received.", Some(first.tag))),
+            };
+            if resp.result.rc == ResultCode::Success {
+                ldap.set_tls();
+                Ok(())
+            } else {
+                Err(LdapError::Result(resp.result))
+            }
+        });
+
+        Box::new(fut)
+    }
+}
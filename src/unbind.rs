@@ -0,0 +1,31 @@
+use asnom::structures::{Tag, Null};
+use asnom::common::TagClass::*;
+
+use futures::Future;
+use futures::sync::oneshot;
+
+use error::LdapError;
+use ldap::Ldap;
+use protocol::LdapOp;
+
+impl Ldap {
+    /// Terminate the session. The server doesn't respond to an Unbind; the
+    /// returned future resolves once the request has been sent and the
+    /// connection has been closed. Operations still in progress fail.
+    pub fn unbind(&self) -> Box<Future<Item = (), Error = LdapError>> {
+        let req = Tag::Null(Null {
+            id: 2,
+            class: Application,
+            inner: (),
+        });
+
+        let (tx, rx) = oneshot::channel();
+        let mut op = LdapOp::new(req);
+        op.closed_tx = Some(tx);
+
+        // The sender is never used; it's dropped when the transport goes away
+        let fut = self.call_op(op).and_then(|_| rx.then(|_| Ok(())));
+
+        Box::new(fut)
+    }
+}
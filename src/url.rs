@@ -0,0 +1,358 @@
+use std::fmt;
+use std::str::FromStr;
+
+use error::LdapError;
+use search::Scope;
+
+/// Scheme of an LDAP URL.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UrlScheme {
+    /// Plain LDAP over TCP, port 389 by default.
+    Ldap,
+    /// LDAP over TLS, port 636 by default.
+    Ldaps,
+    /// LDAP over a Unix domain socket.
+    Ldapi,
+}
+
+impl UrlScheme {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            UrlScheme::Ldap => "ldap",
+            UrlScheme::Ldaps => "ldaps",
+            UrlScheme::Ldapi => "ldapi",
+        }
+    }
+}
+
+/// An extension of an LDAP URL. No extensions are supported by this crate,
+/// so a URL with a critical one can't be acted upon.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UrlExtension {
+    pub critical: bool,
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// An LDAP URL (RFC 4516), e.g. `ldap://host:389/dc=example??sub?(uid=x)`.
+///
+/// All parts are kept percent-decoded, and are encoded again when the URL
+/// is turned back into a string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LdapUrl {
+    pub scheme: UrlScheme,
+    /// Host name or address, without brackets for IPv6; for ldapi, the path
+    /// of the socket, which has its slashes encoded as %2F in the URL.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub dn: String,
+    pub attrs: Vec<String>,
+    /// Search scope; a missing scope means a base object search.
+    pub scope: Option<Scope>,
+    pub filter: Option<String>,
+    pub extensions: Vec<UrlExtension>,
+}
+
+fn url_error(msg: &str) -> LdapError {
+    LdapError::UrlParse(msg.to_string())
+}
+
+fn percent_decode(s: &str) -> Result<String, LdapError> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let hex = |i: usize| bytes.get(i).and_then(|&b| (b as char).to_digit(16));
+        match (hex(i + 1), hex(i + 2)) {
+            (Some(hi), Some(lo)) => out.push((hi * 16 + lo) as u8),
+            _ => return Err(url_error("invalid percent-encoding")),
+        }
+        i += 3;
+    }
+    String::from_utf8(out).map_err(|_| url_error("percent-encoded value isn't UTF-8"))
+}
+
+/// Percent-encode everything but unreserved characters, and the sub-delims,
+/// ':', '@' and '/' not listed in `reserved`. '?' is always encoded.
+fn percent_encode(s: &str, reserved: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        let plain = match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => true,
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' | b'/' =>
+                !reserved.contains(&b),
+            _ => false,
+        };
+        if plain {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+impl LdapUrl {
+    pub fn parse(url: &str) -> Result<LdapUrl, LdapError> {
+        let sep = match url.find("://") {
+            Some(sep) => sep,
+            None => return Err(url_error("missing scheme")),
+        };
+        let scheme = match url[..sep].to_lowercase().as_str() {
+            "ldap" => UrlScheme::Ldap,
+            "ldaps" => UrlScheme::Ldaps,
+            "ldapi" => UrlScheme::Ldapi,
+            _ => return Err(url_error("unknown scheme")),
+        };
+        let rest = &url[sep + 3..];
+        let (hostport, rest) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+
+        let (host, port) = if hostport.starts_with('[') {
+            match hostport.find(']') {
+                Some(end) => (&hostport[1..end], &hostport[end + 1..]),
+                None => return Err(url_error("unterminated IPv6 address")),
+            }
+        } else {
+            match hostport.rfind(':') {
+                Some(pos) => (&hostport[..pos], &hostport[pos..]),
+                None => (hostport, ""),
+            }
+        };
+        let port = match port {
+            "" | ":" => None,
+            port if port.starts_with(':') => match port[1..].parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(_) => return Err(url_error("invalid port")),
+            },
+            _ => return Err(url_error("invalid host")),
+        };
+        let host = if host.is_empty() { None } else { Some(try!(percent_decode(host))) };
+
+        let mut url = LdapUrl {
+            scheme: scheme,
+            host: host,
+            port: port,
+            dn: String::new(),
+            attrs: vec![],
+            scope: None,
+            filter: None,
+            extensions: vec![],
+        };
+        let rest = match rest {
+            Some(rest) => rest,
+            None => return Ok(url),
+        };
+
+        let parts: Vec<&str> = rest.split('?').collect();
+        if parts.len() > 5 {
+            return Err(url_error("too many components"));
+        }
+        url.dn = try!(percent_decode(parts[0]));
+        let part = |i: usize| parts.get(i).map(|part| *part).unwrap_or("");
+        if !part(1).is_empty() {
+            for attr in part(1).split(',') {
+                url.attrs.push(try!(percent_decode(attr)));
+            }
+        }
+        url.scope = match part(2).to_lowercase().as_str() {
+            "base" => Some(Scope::BaseObject),
+            "one" => Some(Scope::SingleLevel),
+            "sub" => Some(Scope::WholeSubtree),
+            "" => None,
+            _ => return Err(url_error("invalid scope")),
+        };
+        if !part(3).is_empty() {
+            url.filter = Some(try!(percent_decode(part(3))));
+        }
+        if !part(4).is_empty() {
+            for ext in part(4).split(',') {
+                let (critical, ext) = if ext.starts_with('!') { (true, &ext[1..]) } else { (false, ext) };
+                let (name, value) = match ext.find('=') {
+                    Some(pos) => (&ext[..pos], Some(try!(percent_decode(&ext[pos + 1..])))),
+                    None => (ext, None),
+                };
+                if name.is_empty() {
+                    return Err(url_error("empty extension name"));
+                }
+                url.extensions.push(UrlExtension {
+                    critical: critical,
+                    name: try!(percent_decode(name)),
+                    value: value,
+                });
+            }
+        }
+        Ok(url)
+    }
+
+    /// The first critical extension, which makes the URL unusable.
+    pub fn critical_extension(&self) -> Option<&UrlExtension> {
+        self.extensions.iter().find(|ext| ext.critical)
+    }
+
+    /// `host:port` of the server, defaulting to localhost and the
+    /// scheme's well-known port.
+    pub(crate) fn host_port(&self) -> String {
+        let host = self.host.as_ref().map(|host| host.as_str()).unwrap_or("localhost");
+        let port = self.port.unwrap_or(if self.scheme == UrlScheme::Ldaps { 636 } else { 389 });
+        if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        }
+    }
+}
+
+impl FromStr for LdapUrl {
+    type Err = LdapError;
+
+    fn from_str(s: &str) -> Result<LdapUrl, LdapError> {
+        LdapUrl::parse(s)
+    }
+}
+
+impl fmt::Display for LdapUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}://", self.scheme.as_str()));
+        match self.host {
+            Some(ref host) if self.scheme == UrlScheme::Ldapi => try!(f.write_str(&percent_encode(host, b"/:"))),
+            Some(ref host) if host.contains(':') => try!(write!(f, "[{}]", host)),
+            Some(ref host) => try!(f.write_str(&percent_encode(host, b""))),
+            None => (),
+        }
+        if let Some(port) = self.port {
+            try!(write!(f, ":{}", port));
+        }
+
+        let mut tail = vec![
+            self.attrs.iter().map(|attr| percent_encode(attr, b",")).collect::<Vec<_>>().join(","),
+            match self.scope {
+                Some(Scope::BaseObject) => "base",
+                Some(Scope::SingleLevel) => "one",
+                Some(Scope::WholeSubtree) => "sub",
+                None => "",
+            }.to_string(),
+            self.filter.as_ref().map(|filter| percent_encode(filter, b"")).unwrap_or(String::new()),
+            self.extensions.iter().map(|ext| {
+                let mut s = if ext.critical { "!".to_string() } else { String::new() };
+                s.push_str(&percent_encode(&ext.name, b",="));
+                if let Some(ref value) = ext.value {
+                    s.push('=');
+                    s.push_str(&percent_encode(value, b","));
+                }
+                s
+            }).collect::<Vec<_>>().join(","),
+        ];
+        while tail.last().map(|part| part.is_empty()).unwrap_or(false) {
+            tail.pop();
+        }
+        if !self.dn.is_empty() || !tail.is_empty() {
+            try!(write!(f, "/{}", percent_encode(&self.dn, b"")));
+            for part in tail {
+                try!(write!(f, "?{}", part));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use search::Scope;
+
+    fn round_trip(s: &str) -> LdapUrl {
+        let url = LdapUrl::parse(s).expect(s);
+        assert_eq!(LdapUrl::parse(&url.to_string()).expect(s), url);
+        url
+    }
+
+    #[test]
+    fn rfc4516_examples() {
+        let url = round_trip("ldap:///o=University%20of%20Michigan,c=US");
+        assert_eq!(url.host, None);
+        assert_eq!(url.dn, "o=University of Michigan,c=US");
+
+        let url = round_trip("ldap://ldap1.example.net/o=University%20of%20Michigan,c=US");
+        assert_eq!(url.host, Some("ldap1.example.net".to_string()));
+        assert_eq!(url.port, None);
+
+        let url = round_trip("ldap://ldap1.example.net/o=University%20of%20Michigan,c=US?postalAddress");
+        assert_eq!(url.attrs, vec!["postalAddress".to_string()]);
+
+        let url = round_trip("ldap://ldap1.example.net:6666/o=University%20of%20Michigan,c=US??sub?(cn=Babs%20Jensen)");
+        assert_eq!(url.port, Some(6666));
+        assert_eq!(url.scope, Some(Scope::WholeSubtree));
+        assert_eq!(url.filter, Some("(cn=Babs Jensen)".to_string()));
+
+        let url = round_trip("LDAP://ldap1.example.com/c=GB?objectClass?ONE");
+        assert_eq!(url.scheme, UrlScheme::Ldap);
+        assert_eq!(url.attrs, vec!["objectClass".to_string()]);
+        assert_eq!(url.scope, Some(Scope::SingleLevel));
+
+        let url = round_trip("ldap://ldap2.example.com/o=Question%3f,c=US?mail");
+        assert_eq!(url.dn, "o=Question?,c=US");
+
+        let url = round_trip("ldap://ldap3.example.com/o=Babsco,c=US???(four-octet=%5c00%5c00%5c00%5c04)");
+        assert_eq!(url.filter, Some("(four-octet=\\00\\00\\00\\04)".to_string()));
+
+        let url = round_trip("ldap://ldap.example.com/o=An%20Example%5C2C%20Inc.,c=US");
+        assert_eq!(url.dn, "o=An Example\\2C Inc.,c=US");
+
+        let plain = round_trip("ldap://ldap.example.net");
+        assert_eq!(round_trip("ldap://ldap.example.net/"), plain);
+        assert_eq!(round_trip("ldap://ldap.example.net/?"), plain);
+    }
+
+    #[test]
+    fn ipv6_literal() {
+        let url = round_trip("ldap://[2001:db8::1]:3389/dc=example,dc=com");
+        assert_eq!(url.host, Some("2001:db8::1".to_string()));
+        assert_eq!(url.port, Some(3389));
+        assert_eq!(url.host_port(), "[2001:db8::1]:3389");
+        assert_eq!(url.to_string(), "ldap://[2001:db8::1]:3389/dc=example,dc=com");
+    }
+
+    #[test]
+    fn ldapi_path() {
+        let url = round_trip("ldapi://%2Fvar%2Frun%2Fslapd%2Fldapi/dc=example,dc=com");
+        assert_eq!(url.scheme, UrlScheme::Ldapi);
+        assert_eq!(url.host, Some("/var/run/slapd/ldapi".to_string()));
+        assert_eq!(url.dn, "dc=example,dc=com");
+        assert_eq!(url.to_string(), "ldapi://%2Fvar%2Frun%2Fslapd%2Fldapi/dc=example,dc=com");
+    }
+
+    #[test]
+    fn extensions() {
+        let url = round_trip("ldap:///??sub??e-bindname=cn=Manager%2cdc=example%2cdc=com");
+        assert_eq!(url.extensions, vec![UrlExtension {
+            critical: false,
+            name: "e-bindname".to_string(),
+            value: Some("cn=Manager,dc=example,dc=com".to_string()),
+        }]);
+        assert!(url.critical_extension().is_none());
+
+        let url = round_trip("ldap:///??sub??!e-bindname=cn=Manager%2cdc=example%2cdc=com");
+        assert!(url.extensions[0].critical);
+        assert_eq!(url.critical_extension().map(|ext| ext.name.as_str()), Some("e-bindname"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(LdapUrl::parse("ldap:///o=%zz").is_err());
+        assert!(LdapUrl::parse("ldap:///o=%4").is_err());
+        assert!(LdapUrl::parse("ldap:///o=%ff").is_err());
+        assert!(LdapUrl::parse("ldap:///o=%+1").is_err());
+        assert!(LdapUrl::parse("ldap:///o=%-1").is_err());
+        assert!(LdapUrl::parse("ldap:///o=x??subtree").is_err());
+        assert!(LdapUrl::parse("ldap://host:port/").is_err());
+        assert!(LdapUrl::parse("http://host/").is_err());
+    }
+}